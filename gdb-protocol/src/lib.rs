@@ -0,0 +1,659 @@
+//! GDB remote serial protocol
+//!
+//! Packets are framed as `$<data>#<checksum>` and acknowledged with `+`, or `-` to request
+//! retransmission. Only `core` is used here: the stopped machine is accessed through `Target` and the
+//! debugger through `Connection`, so exchanges can be replayed from recorded sessions by the host
+//! tests.
+
+#![no_std]
+#![deny(missing_docs)]
+
+use core::fmt::{self, Write};
+
+/// Maximum length of packet data, advertised to the debugger
+pub const PACKET_SIZE: usize = 4096;
+
+/// Number of registers in the `g` packet
+pub const REGISTER_COUNT: usize = 24;
+
+/// Register number of the instruction pointer
+pub const RIP: usize = 16;
+
+/// Register number of the flags register
+pub const EFLAGS: usize = 17;
+
+/// Signal reported when stopping at a breakpoint or after a single step
+pub const SIGTRAP: u8 = 5;
+
+/// Registers of the stopped machine, in the order of GDB's x86_64 register numbers
+///
+/// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8-r15 and rip are 8 bytes, followed by the 4 byte eflags,
+/// cs, ss, ds, es, fs and gs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers(pub [u64; REGISTER_COUNT]);
+
+/// Size in bytes of register `n`
+fn register_size(n: usize) -> usize {
+    if n < EFLAGS {
+        8
+    } else {
+        4
+    }
+}
+
+/// Memory could not be accessed
+#[derive(Debug)]
+pub struct Fault;
+
+/// Byte stream to the debugger
+pub trait Connection {
+    /// Blocks until a byte is received
+    fn read(&mut self) -> u8;
+
+    /// Writes all of `bytes`
+    fn write(&mut self, bytes: &[u8]);
+}
+
+/// Stopped machine being debugged
+pub trait Target {
+    /// Gets the registers of the stopped context
+    fn registers(&self) -> Registers;
+
+    /// Replaces the registers of the stopped context
+    fn set_registers(&mut self, registers: &Registers);
+
+    /// Reads memory starting at `address` into `data`
+    fn read_memory(&mut self, address: usize, data: &mut [u8]) -> Result<(), Fault>;
+
+    /// Writes `data` to memory starting at `address`
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), Fault>;
+
+    /// Inserts a software breakpoint at `address`
+    fn insert_breakpoint(&mut self, address: usize) -> Result<(), Fault>;
+
+    /// Removes a software breakpoint at `address`
+    fn remove_breakpoint(&mut self, address: usize) -> Result<(), Fault>;
+}
+
+/// How the debugger asked for the target to be resumed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Run until the next breakpoint
+    Continue,
+    /// Execute a single instruction
+    Step,
+    /// Run without the debugger attached
+    Detach,
+    /// Terminate the target
+    Kill,
+}
+
+/// Protocol state of a debugging session
+pub struct Stub<C> {
+    connection: C,
+    /// Whether the debugger is waiting for a stop reply
+    running: bool,
+    request: [u8; PACKET_SIZE],
+    response: Response,
+}
+
+impl<C: Connection> Stub<C> {
+    /// Creates a stub talking to the debugger over `connection`
+    pub fn new(connection: C) -> Self {
+        Self {
+            connection,
+            running: false,
+            request: [0; PACKET_SIZE],
+            response: Response::new(),
+        }
+    }
+
+    /// Gets the connection to the debugger
+    pub fn connection(&mut self) -> &mut C {
+        &mut self.connection
+    }
+
+    /// Reports that `target` stopped with `signal` and serves requests until it is resumed
+    pub fn stop<T: Target>(&mut self, target: &mut T, signal: u8) -> Resume {
+        if self.running {
+            self.running = false;
+            self.response.clear();
+            write!(self.response, "S{:02x}", signal).unwrap();
+            self.send();
+        }
+
+        loop {
+            let len = self.receive();
+            self.response.clear();
+
+            match handle(&mut self.response, target, signal, &self.request[..len]) {
+                // killing the target does not expect a reply
+                Some(Resume::Kill) => return Resume::Kill,
+                Some(Resume::Detach) => {
+                    self.send();
+                    return Resume::Detach;
+                }
+                Some(resume) => {
+                    self.running = true;
+                    return resume;
+                }
+                None => self.send(),
+            }
+        }
+    }
+
+    /// Receives the next valid packet into `self.request`, returning its length
+    fn receive(&mut self) -> usize {
+        loop {
+            // skip acknowledgements and interrupts until the start of a packet
+            while self.connection.read() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum = 0u8;
+            let mut overflow = false;
+
+            loop {
+                let byte = self.connection.read();
+                if byte == b'#' {
+                    break;
+                }
+
+                checksum = checksum.wrapping_add(byte);
+
+                match self.request.get_mut(len) {
+                    Some(slot) => *slot = byte,
+                    None => overflow = true,
+                }
+                len += 1;
+            }
+
+            let expected = [self.connection.read(), self.connection.read()];
+
+            if !overflow && decode_le(&expected) == Some(u64::from(checksum)) {
+                self.connection.write(b"+");
+                return len;
+            }
+
+            self.connection.write(b"-");
+        }
+    }
+
+    /// Sends `self.response` as a packet, retransmitting until it is acknowledged
+    fn send(&mut self) {
+        let data = self.response.as_bytes();
+        let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+        let mut trailer = [b'#', 0, 0];
+        encode_hex(&[checksum], &mut trailer[1..]);
+
+        loop {
+            self.connection.write(b"$");
+            self.connection.write(data);
+            self.connection.write(&trailer);
+
+            match self.connection.read() {
+                b'-' => continue,
+                _ => return,
+            }
+        }
+    }
+}
+
+/// Number of bytes of memory transferred at a time, bounding stack usage
+const CHUNK_SIZE: usize = 64;
+
+/// Handles a single request, writing the reply to `response` or returning how to resume
+fn handle<T: Target>(
+    response: &mut Response,
+    target: &mut T,
+    signal: u8,
+    request: &[u8],
+) -> Option<Resume> {
+    let (&command, args) = request.split_first()?;
+
+    match command {
+        b'?' => write!(response, "S{:02x}", signal).unwrap(),
+        b'g' => {
+            let registers = target.registers();
+            for (n, value) in registers.0.iter().enumerate() {
+                response.push_hex(&value.to_le_bytes()[..register_size(n)]);
+            }
+        }
+        b'G' => {
+            let mut registers = target.registers();
+            let mut args = args;
+
+            for (n, value) in registers.0.iter_mut().enumerate() {
+                let size = register_size(n);
+                match args.get(..size * 2).and_then(decode_le) {
+                    Some(v) => *value = v,
+                    None => break,
+                }
+                args = &args[size * 2..];
+            }
+
+            target.set_registers(&registers);
+            response.push_str("OK");
+        }
+        b'p' => match parse_hex(args).filter(|n| *n < REGISTER_COUNT) {
+            Some(n) => {
+                let value = target.registers().0[n];
+                response.push_hex(&value.to_le_bytes()[..register_size(n)]);
+            }
+            None => response.push_str("E00"),
+        },
+        b'P' => {
+            let value = split(args, b'=').and_then(|(n, hex)| {
+                let n = parse_hex(n).filter(|n| *n < REGISTER_COUNT)?;
+                Some((n, decode_le(hex)?))
+            });
+
+            match value {
+                Some((n, value)) => {
+                    let mut registers = target.registers();
+                    registers.0[n] = value;
+                    target.set_registers(&registers);
+                    response.push_str("OK");
+                }
+                None => response.push_str("E00"),
+            }
+        }
+        b'm' => match parse_range(args).filter(|(_, len)| *len <= PACKET_SIZE / 2) {
+            Some((address, len)) => {
+                let mut chunk = [0; CHUNK_SIZE];
+
+                for offset in (0..len).step_by(CHUNK_SIZE) {
+                    let chunk = &mut chunk[..CHUNK_SIZE.min(len - offset)];
+
+                    if let Err(Fault) = target.read_memory(address + offset, chunk) {
+                        response.clear();
+                        response.push_str("E14");
+                        break;
+                    }
+
+                    response.push_hex(chunk);
+                }
+            }
+            None => response.push_str("E00"),
+        },
+        b'M' => {
+            let write = split(args, b':').and_then(|(range, hex)| {
+                let (address, len) = parse_range(range)?;
+                if len.checked_mul(2) != Some(hex.len()) {
+                    return None;
+                }
+
+                let mut chunk = [0; CHUNK_SIZE];
+
+                for (i, hex) in hex.chunks(CHUNK_SIZE * 2).enumerate() {
+                    let chunk = &mut chunk[..hex.len() / 2];
+                    decode_hex(hex, chunk)?;
+
+                    if let Err(Fault) = target.write_memory(address + i * CHUNK_SIZE, chunk) {
+                        return Some(Err(Fault));
+                    }
+                }
+
+                Some(Ok(()))
+            });
+
+            match write {
+                Some(Ok(())) => response.push_str("OK"),
+                Some(Err(Fault)) => response.push_str("E14"),
+                None => response.push_str("E00"),
+            }
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(args) {
+                let mut registers = target.registers();
+                registers.0[RIP] = address as u64;
+                target.set_registers(&registers);
+            }
+
+            return Some(if command == b'c' {
+                Resume::Continue
+            } else {
+                Resume::Step
+            });
+        }
+        b'Z' | b'z' => {
+            // only software breakpoints are supported, other kinds receive an empty reply
+            let address = args
+                .strip_prefix(b"0,")
+                .and_then(|args| split(args, b','))
+                .and_then(|(address, _)| parse_hex(address));
+
+            if let Some(address) = address {
+                let result = if command == b'Z' {
+                    target.insert_breakpoint(address)
+                } else {
+                    target.remove_breakpoint(address)
+                };
+
+                match result {
+                    Ok(()) => response.push_str("OK"),
+                    Err(Fault) => response.push_str("E14"),
+                }
+            }
+        }
+        b'q' => {
+            if args.starts_with(b"Supported") {
+                write!(response, "PacketSize={:x}", PACKET_SIZE).unwrap();
+            } else if args == b"Attached" {
+                response.push_str("1");
+            } else if args == b"fThreadInfo" {
+                response.push_str("m1");
+            } else if args == b"sThreadInfo" {
+                response.push_str("l");
+            } else if args == b"C" {
+                response.push_str("QC1");
+            }
+        }
+        // there is a single thread, so selecting it always succeeds
+        b'H' | b'T' => response.push_str("OK"),
+        b'D' => {
+            response.push_str("OK");
+            return Some(Resume::Detach);
+        }
+        b'k' => return Some(Resume::Kill),
+        // unsupported requests receive an empty reply
+        _ => {}
+    }
+
+    None
+}
+
+/// Packet data being built in a fixed buffer, as the stopped target may hold the heap lock
+struct Response {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Response {
+    const fn new() -> Self {
+        Self {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    fn push_str(&mut self, s: &str) {
+        self.write_str(s).unwrap();
+    }
+
+    fn push_hex(&mut self, bytes: &[u8]) {
+        let end = self.len + bytes.len() * 2;
+        encode_hex(bytes, &mut self.data[self.len..end]);
+        self.len = end;
+    }
+}
+
+impl Write for Response {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.data
+            .get_mut(self.len..end)
+            .ok_or(fmt::Error)?
+            .copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Splits `bytes` at the first `separator`
+fn split(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let index = bytes.iter().position(|b| *b == separator)?;
+    Some((&bytes[..index], &bytes[index + 1..]))
+}
+
+/// Parses an `<address>,<length>` pair, rejecting ranges that wrap around the address space
+fn parse_range(bytes: &[u8]) -> Option<(usize, usize)> {
+    let (address, len) = split(bytes, b',')?;
+    let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+
+    address.checked_add(len)?;
+
+    Some((address, len))
+}
+
+/// Parses a big endian hexadecimal number, as used for addresses and lengths
+fn parse_hex(bytes: &[u8]) -> Option<usize> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+
+    bytes
+        .iter()
+        .try_fold(0, |value, byte| Some(value << 4 | nibble(*byte)? as usize))
+}
+
+/// Decodes little endian hexadecimal bytes, as used for register values
+fn decode_le(hex: &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    decode_hex(hex, bytes.get_mut(..hex.len() / 2)?)?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Decodes pairs of hexadecimal digits in `hex` into `bytes`, which must be exactly half its length
+fn decode_hex(hex: &[u8], bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 {
+        return None;
+    }
+
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = nibble(pair[0])? << 4 | nibble(pair[1])?;
+    }
+
+    Some(())
+}
+
+/// Encodes `bytes` as pairs of lowercase hexadecimal digits into `hex`
+fn encode_hex(bytes: &[u8], hex: &mut [u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    for (byte, pair) in bytes.iter().zip(hex.chunks_mut(2)) {
+        pair[0] = DIGITS[(byte >> 4) as usize];
+        pair[1] = DIGITS[(byte & 0xf) as usize];
+    }
+}
+
+/// Value of a hexadecimal digit
+fn nibble(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|n| n as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use {super::*, std::vec::Vec};
+
+    /// Connection replaying bytes recorded from a GDB session
+    struct Recording {
+        input: &'static [u8],
+        output: Vec<u8>,
+    }
+
+    impl Connection for Recording {
+        fn read(&mut self) -> u8 {
+            let (byte, rest) = self
+                .input
+                .split_first()
+                .expect("Stub read past the end of the recording");
+            self.input = rest;
+            *byte
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            self.output.extend_from_slice(bytes);
+        }
+    }
+
+    /// Target with 4 bytes of memory at 0x1000
+    struct Mock {
+        registers: Registers,
+        memory: [u8; 4],
+        breakpoints: Vec<usize>,
+    }
+
+    impl Target for Mock {
+        fn registers(&self) -> Registers {
+            self.registers
+        }
+
+        fn set_registers(&mut self, registers: &Registers) {
+            self.registers = *registers;
+        }
+
+        fn read_memory(&mut self, address: usize, data: &mut [u8]) -> Result<(), Fault> {
+            let offset = address.checked_sub(0x1000).ok_or(Fault)?;
+            data.copy_from_slice(self.memory.get(offset..offset + data.len()).ok_or(Fault)?);
+            Ok(())
+        }
+
+        fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), Fault> {
+            let offset = address.checked_sub(0x1000).ok_or(Fault)?;
+            self.memory
+                .get_mut(offset..offset + data.len())
+                .ok_or(Fault)?
+                .copy_from_slice(data);
+            Ok(())
+        }
+
+        fn insert_breakpoint(&mut self, address: usize) -> Result<(), Fault> {
+            self.breakpoints.push(address);
+            Ok(())
+        }
+
+        fn remove_breakpoint(&mut self, address: usize) -> Result<(), Fault> {
+            self.breakpoints.retain(|a| *a != address);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session() {
+        let mut target = Mock {
+            registers: Registers::default(),
+            memory: [0xde, 0xad, 0xbe, 0xef],
+            breakpoints: Vec::new(),
+        };
+
+        let mut stub = Stub::new(Recording {
+            input: b"+$qSupported:multiprocess+;swbreak+;hwbreak+;qRelocInsn+#c9+\
+                     $?#3f+\
+                     $vMustReplyEmpty#3a+\
+                     $m1000,4#8e+\
+                     $m2000,1#8c+\
+                     $m1000,4#00$m1000,4#8e-+\
+                     $M1002,2:1234#72+\
+                     $Z0,1000,1#d4+\
+                     $P10=0020000000000000#f0+\
+                     $c#63",
+            output: Vec::new(),
+        });
+
+        assert_eq!(stub.stop(&mut target, SIGTRAP), Resume::Continue);
+        assert_eq!(
+            stub.connection().output,
+            b"+$PacketSize=1000#f1\
+              +$S05#b8\
+              +$#00\
+              +$deadbeef#20\
+              +$E14#aa\
+              -+$deadbeef#20$deadbeef#20\
+              +$OK#9a\
+              +$OK#9a\
+              +$OK#9a\
+              +"
+        );
+        assert_eq!(target.registers.0[RIP], 0x2000);
+        assert_eq!(target.memory, [0xde, 0xad, 0x12, 0x34]);
+        assert_eq!(target.breakpoints, [0x1000]);
+
+        // the next stop is reported before serving requests
+        stub.connection().input = b"+$z0,1000,1#f4+$p10#d1+$s#73";
+        stub.connection().output.clear();
+
+        assert_eq!(stub.stop(&mut target, SIGTRAP), Resume::Step);
+        assert_eq!(
+            stub.connection().output,
+            b"$S05#b8+$OK#9a+$0020000000000000#02+"
+        );
+        assert!(target.breakpoints.is_empty());
+
+        stub.connection().input = b"+$D#44+";
+        stub.connection().output.clear();
+
+        assert_eq!(stub.stop(&mut target, SIGTRAP), Resume::Detach);
+        assert_eq!(stub.connection().output, b"$S05#b8+$OK#9a");
+    }
+
+    #[test]
+    fn registers() {
+        let mut target = Mock {
+            registers: Registers::default(),
+            memory: [0; 4],
+            breakpoints: Vec::new(),
+        };
+        target.registers.0[RIP] = 0x1000;
+        target.registers.0[EFLAGS] = 0x246;
+
+        let mut stub = Stub::new(Recording {
+            input: b"$p10#d1+\
+                     $p11#d2+\
+                     $p18#d9+\
+                     $P11=47020000#7c+\
+                     $k#6b",
+            output: Vec::new(),
+        });
+
+        assert_eq!(stub.stop(&mut target, SIGTRAP), Resume::Kill);
+        assert_eq!(
+            stub.connection().output,
+            b"+$0010000000000000#01\
+              +$46020000#8c\
+              +$E00#a5\
+              +$OK#9a\
+              +"
+        );
+        assert_eq!(target.registers.0[EFLAGS], 0x247);
+    }
+
+    #[test]
+    fn overflow() {
+        let mut target = Mock {
+            registers: Registers::default(),
+            memory: [0; 4],
+            breakpoints: Vec::new(),
+        };
+
+        // ranges that wrap around the address space or whose length overflows when doubled
+        let mut stub = Stub::new(Recording {
+            input: b"$mffffffffffffffc0,80#28+\
+                     $Mffffffffffffffff,2:1234#0f+\
+                     $m0,8000000000000000#d1+\
+                     $M1000,8000000000000000:00#dc+\
+                     $k#6b",
+            output: Vec::new(),
+        });
+
+        assert_eq!(stub.stop(&mut target, SIGTRAP), Resume::Kill);
+        assert_eq!(
+            stub.connection().output,
+            b"+$E00#a5\
+              +$E00#a5\
+              +$E00#a5\
+              +$E00#a5\
+              +"
+        );
+    }
+}
@@ -17,9 +17,13 @@ trap "rm -f $temp_file" 0 2 3 15
 # create config fuke
 echo "kernel = \"$1\"" >> $temp_file
 echo "memory = 32" >> $temp_file
+# number of vCPUs, e.g. STARDUST_VCPUS=4
+echo "vcpus = ${STARDUST_VCPUS:-1}" >> $temp_file
 echo "name = \"stardust\"" >> $temp_file
 echo "on_crash = 'destroy'" >> $temp_file
 echo "vif = ['bridge=xenbr0']" >> $temp_file
+# kernel command line options, e.g. STARDUST_CMDLINE="log.colour=off"
+echo "extra = \"$STARDUST_CMDLINE\"" >> $temp_file
 
 # start VM
 sudo xl create -c $temp_file
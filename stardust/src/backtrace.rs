@@ -0,0 +1,28 @@
+//! Backtrace logging
+//!
+//! Return addresses are logged as `#<n> <address>` lines, resolved on the host against the kernel ELF
+//! by `symbolize.sh`.
+
+use {
+    core::sync::atomic::{AtomicBool, Ordering},
+    log::error,
+    xen::platform::backtrace::Backtrace,
+};
+
+/// Set once a backtrace has been logged
+static LOGGED: AtomicBool = AtomicBool::new(false);
+
+/// Logs the return addresses of `backtrace`, called on panic so must not allocate
+///
+/// Only the first backtrace is logged, as a fatal trap logs the interrupted context before panicking.
+pub fn log(backtrace: Backtrace) {
+    if LOGGED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    error!("backtrace:");
+
+    for (i, address) in backtrace.enumerate() {
+        error!("  #{} {:#018x}", i, address);
+    }
+}
@@ -0,0 +1,48 @@
+//! Kernel configuration
+//!
+//! Options are supplied as whitespace separated `key=value` pairs on the domain command line (the
+//! `extra` field of the Xen domain configuration), for example `log.colour=off`.
+
+use {
+    core::{slice, str},
+    xen::START_INFO,
+};
+
+/// Gets the value of a command line option
+///
+/// Options present without a value (`key` rather than `key=value`) return an empty string.
+pub fn get(key: &str) -> Option<&'static str> {
+    cmd_line().split_whitespace().find_map(|option| {
+        let (k, v) = option.split_once('=').unwrap_or((option, ""));
+        if k == key {
+            Some(v)
+        } else {
+            None
+        }
+    })
+}
+
+/// Gets the value of a boolean command line option
+///
+/// Options present without a value are treated as enabled.
+pub fn flag(key: &str) -> Option<bool> {
+    get(key).and_then(|value| match value {
+        "" | "1" | "on" | "true" | "yes" => Some(true),
+        "0" | "off" | "false" | "no" => Some(false),
+        _ => {
+            log::warn!("invalid value {:?} for boolean option {:?}", value, key);
+            None
+        }
+    })
+}
+
+/// Gets the nul-terminated command line from the start info page
+fn cmd_line() -> &'static str {
+    // SAFETY: START_INFO is initialised before any configuration is read and lives for the lifetime of the kernel
+    let cmd_line = unsafe { &(*START_INFO).cmd_line };
+
+    let bytes = unsafe { slice::from_raw_parts(cmd_line.as_ptr() as *const u8, cmd_line.len()) };
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
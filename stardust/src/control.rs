@@ -0,0 +1,185 @@
+//! Domain control
+//!
+//! Watches `control/shutdown` for requests from the toolstack (such as `xl shutdown`, `xl reboot` and
+//! `xl save`), notifying registered subsystems before stopping the executor or suspending the domain.
+
+use {
+    crate::smp,
+    alloc::vec::Vec,
+    core::sync::atomic::{AtomicU8, Ordering},
+    log::{info, warn},
+    spin::Mutex,
+    xen::{
+        hypercall,
+        scheduler::ShutdownReason,
+        suspend::{self, Resume},
+        xenbus, xenstore,
+    },
+};
+
+/// Watch token for `control/shutdown`
+const SHUTDOWN_TOKEN: &str = "control/shutdown\0";
+
+/// Features advertised to the toolstack
+const FEATURES: [&str; 3] = [
+    "control/feature-poweroff\0",
+    "control/feature-reboot\0",
+    "control/feature-suspend\0",
+];
+
+/// Handlers called before the domain shuts down or suspends
+static HANDLERS: Mutex<Vec<fn(Request)>> = Mutex::new(Vec::new());
+
+/// Handlers called after the domain resumes in a new domain
+static RESUME_HANDLERS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+/// Most recent shutdown request, stored as the discriminant of `Request`
+static REQUEST: AtomicU8 = AtomicU8::new(Request::Poweroff as u8);
+
+/// Shutdown request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    /// Power off the domain
+    Poweroff,
+    /// Reboot the domain
+    Reboot,
+    /// Halt the domain, treated as a power off
+    Halt,
+    /// Suspend the domain to be saved or migrated
+    Suspend,
+}
+
+impl Request {
+    /// Parse the value of `control/shutdown`
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "poweroff" => Some(Request::Poweroff),
+            "reboot" => Some(Request::Reboot),
+            "halt" => Some(Request::Halt),
+            "suspend" => Some(Request::Suspend),
+            _ => None,
+        }
+    }
+
+    /// Reason reported to the hypervisor when shutting down for this request
+    pub fn reason(self) -> ShutdownReason {
+        match self {
+            Request::Poweroff | Request::Halt => ShutdownReason::Poweroff,
+            Request::Reboot => ShutdownReason::Reboot,
+            Request::Suspend => ShutdownReason::Suspend,
+        }
+    }
+}
+
+/// Registers a handler to be called before the domain shuts down or suspends
+pub fn register(handler: fn(Request)) {
+    HANDLERS.lock().push(handler);
+}
+
+/// Registers a handler to be called after resuming in a new domain, where frontends must reconnect
+pub fn register_resume(handler: fn()) {
+    RESUME_HANDLERS.lock().push(handler);
+}
+
+/// Notifies registered handlers then suspends the domain, returning once it has resumed
+///
+/// Execution continues in the original domain if the suspend fails.
+pub async fn suspend() -> Result<Resume, hypercall::Error> {
+    notify(Request::Suspend);
+
+    log::logger().flush();
+
+    smp::park();
+    let result = suspend::suspend();
+    smp::unpark(match result {
+        Ok(resume) => resume,
+        Err(_) => Resume::Cancelled,
+    });
+    let resume = result?;
+
+    if resume == Resume::NewDomain {
+        xenbus::resume().await;
+
+        let handlers = RESUME_HANDLERS.lock().clone();
+        for handler in handlers {
+            handler();
+        }
+    }
+
+    Ok(resume)
+}
+
+/// Notifies registered handlers, flushes logs and stops the executor
+///
+/// The kernel issues the shutdown once the executor has stopped.
+pub fn shutdown(request: Request) {
+    info!("shutdown requested: {:?}", request);
+
+    REQUEST.store(request as u8, Ordering::Relaxed);
+
+    notify(request);
+
+    log::logger().flush();
+
+    crate::executor::stop();
+}
+
+/// Call all registered handlers with the supplied request
+fn notify(request: Request) {
+    // copy handlers out so they may themselves register or shut down
+    let handlers = HANDLERS.lock().clone();
+    for handler in handlers {
+        handler(request);
+    }
+}
+
+/// Gets the most recent shutdown request, `Request::Poweroff` if none has been made
+pub fn request() -> Request {
+    match REQUEST.load(Ordering::Relaxed) {
+        r if r == Request::Reboot as u8 => Request::Reboot,
+        r if r == Request::Halt as u8 => Request::Halt,
+        _ => Request::Poweroff,
+    }
+}
+
+/// Control task, waits for shutdown requests from the toolstack
+pub async fn task() {
+    for feature in FEATURES.iter() {
+        xenstore::write(feature, "1").expect("Failed to write control feature");
+    }
+
+    xenbus::watch(SHUTDOWN_TOKEN, SHUTDOWN_TOKEN).await;
+
+    loop {
+        xenbus::watch_event(SHUTDOWN_TOKEN).await;
+
+        let value = xenstore::read(SHUTDOWN_TOKEN).expect("Failed to read shutdown request");
+
+        // the watch also fires when the node is created or cleared
+        if value.is_empty() {
+            continue;
+        }
+
+        let request = match Request::parse(&value) {
+            Some(request) => request,
+            None => {
+                warn!("unsupported shutdown request {:?}", value);
+                continue;
+            }
+        };
+
+        // acknowledge by clearing the node, the toolstack waits for this before timing out
+        xenstore::write(SHUTDOWN_TOKEN, "").expect("Failed to clear shutdown request");
+
+        if request == Request::Suspend {
+            if let Err(e) = suspend().await {
+                warn!("failed to suspend: {}", e);
+            }
+            continue;
+        }
+
+        shutdown(request);
+
+        return;
+    }
+}
@@ -0,0 +1,141 @@
+//! Crash records
+//!
+//! On panic a compact record of the crash is written to `data/crash` in the domain's XenStore
+//! directory, where monitoring can read it after the domain and its console are destroyed. The record
+//! holds the uptime, panic message and location, backtrace and the most recent log records:
+//!
+//! ```text
+//! uptime: 12.345678
+//! message: attempt to add with overflow
+//! location: stardust/src/net/mod.rs:42:5
+//! backtrace: 0x1f2e3 0x1a2b3 0x10c4d
+//! log:
+//! [   12.345670] ERROR stardust: panicked at 'attempt to add with overflow', ...
+//! ```
+
+use {
+    crate::logger::buffer::{truncate, BUFFER},
+    core::{
+        cmp::max,
+        fmt::{self, Write},
+        panic::PanicInfo,
+        str,
+    },
+    log::warn,
+    spin::Mutex,
+    xen::{platform::backtrace::Backtrace, time::get_monotonic_time, xenstore},
+};
+
+/// Maximum size of a crash record, below the 4096 byte XenStore payload limit leaving room for the key
+const RECORD_SIZE: usize = 4000;
+
+/// Number of records from the end of the log buffer included
+const LOG_TAIL: u64 = 16;
+
+/// Maximum length of each included log message, longer messages are truncated
+const MAX_LOG_MESSAGE_LEN: usize = 160;
+
+/// Maximum number of backtrace addresses included
+const MAX_FRAMES: usize = 16;
+
+/// Crash record being formatted, static so a record can be built with little stack left
+static RECORD: Mutex<Record> = Mutex::new(Record::new());
+
+/// Fixed size buffer for formatting the crash record without allocating
+///
+/// Output beyond `RECORD_SIZE` bytes is silently discarded.
+struct Record {
+    buf: [u8; RECORD_SIZE],
+    len: usize,
+}
+
+impl Record {
+    const fn new() -> Self {
+        Self {
+            buf: [0; RECORD_SIZE],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    fn format(&mut self, info: &PanicInfo) -> fmt::Result {
+        let uptime = get_monotonic_time();
+        writeln!(
+            self,
+            "uptime: {}.{:06}",
+            uptime / 1_000_000_000,
+            (uptime % 1_000_000_000) / 1_000
+        )?;
+
+        match info.message() {
+            Some(message) => writeln!(self, "message: {}", message)?,
+            None => writeln!(self, "message: unknown")?,
+        }
+
+        if let Some(location) = info.location() {
+            writeln!(self, "location: {}", location)?;
+        }
+
+        write!(self, "backtrace:")?;
+        for address in Backtrace::current().take(MAX_FRAMES) {
+            write!(self, " {:#x}", address)?;
+        }
+        writeln!(self)?;
+
+        writeln!(self, "log:")?;
+
+        // the panic may have occurred while logging
+        if let Some(buffer) = BUFFER.try_lock() {
+            let start = max(
+                buffer.first_sequence(),
+                buffer.next_sequence().saturating_sub(LOG_TAIL),
+            );
+
+            let mut sequence = start;
+            while let Some(entry) = buffer.read(sequence) {
+                writeln!(
+                    self,
+                    "[{:>5}.{:06}] {} {}: {}",
+                    entry.timestamp / 1_000_000_000,
+                    (entry.timestamp % 1_000_000_000) / 1_000,
+                    entry.level,
+                    entry.target(),
+                    truncate(entry.message(), MAX_LOG_MESSAGE_LEN)
+                )?;
+                sequence = entry.sequence + 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let s = truncate(s, RECORD_SIZE - self.len);
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Writes a record of the panic described by `info` to XenStore, must not allocate
+pub fn write(info: &PanicInfo) {
+    // a panic while writing the record leaves it locked
+    let mut record = match RECORD.try_lock() {
+        Some(record) => record,
+        None => return,
+    };
+
+    record.len = 0;
+    // output that does not fit is truncated rather than failing
+    let _ = record.format(info);
+
+    // the panicking code may have been part way through a XenStore request
+    if !xenstore::try_write("data/crash\0", record.as_str()) {
+        warn!("XenStore in use, crash record not written");
+    }
+}
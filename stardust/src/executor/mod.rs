@@ -1,19 +1,188 @@
 //! Futures executor for cooperative multitasking
+//!
+//! Each vCPU runs its own executor. Tasks spawned on an `Executor` are only polled by it, while
+//! tasks spawned with `spawn_shared` are queued on the spawning vCPU and may be stolen by an idle one.
 
 use {
-    alloc::collections::VecDeque,
+    crate::mm::stats,
+    alloc::{
+        collections::{BTreeMap, VecDeque},
+        vec::Vec,
+    },
     core::{
         future::Future,
+        sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
     },
-    task::Task,
+    log::warn,
+    spin::Mutex,
+    task::{SharedTask, Task},
+    xen::{
+        scheduler::{schedule_operation, Command},
+        suspend,
+        time::get_monotonic_time,
+        vcpu::{self, MAX_VCPUS},
+    },
 };
 
+pub use task::TaskId;
+
 mod task;
 
+/// Information about every task that has been spawned and not yet completed
+static TASKS: Mutex<BTreeMap<TaskId, TaskInfo>> = Mutex::new(BTreeMap::new());
+
+/// Shared tasks waiting to be polled, queued per vCPU
+static SHARED: [Mutex<VecDeque<SharedTask>>; MAX_VCPUS] = {
+    const EMPTY: Mutex<VecDeque<SharedTask>> = Mutex::new(VecDeque::new());
+    [EMPTY; MAX_VCPUS]
+};
+
+/// Number of shared tasks spawned and not yet completed, including those being polled
+static SHARED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set to stop the executor, cancelling all remaining tasks
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Set to return from `run_until_stopped` without cancelling tasks, so secondary vCPUs can be taken down
+static PARK: AtomicBool = AtomicBool::new(false);
+
+/// Nanoseconds a single poll of a task may take before the executor is considered stalled
+static STALL_THRESHOLD: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// First poll that exceeded `STALL_THRESHOLD`, cleared once that task yields within it
+static STALL: Mutex<Option<Stall>> = Mutex::new(None);
+
+/// Poll in progress on each vCPU, so a task that never yields is detected before its poll returns
+static POLLING: [Mutex<Option<Polling>>; MAX_VCPUS] = {
+    const IDLE: Mutex<Option<Polling>> = Mutex::new(None);
+    [IDLE; MAX_VCPUS]
+};
+
+/// Poll of a task that did not yield within the stall threshold
+#[derive(Debug, Clone)]
+pub struct Stall {
+    /// Unique identifier of the task
+    pub id: TaskId,
+    /// Name supplied when the task was spawned
+    pub name: &'static str,
+    /// Nanoseconds before the task yielded, or spent so far if it has not
+    pub duration: u64,
+}
+
+/// Poll of a task in progress
+struct Polling {
+    id: TaskId,
+    name: &'static str,
+    /// Monotonic time at which the poll started
+    start: u64,
+    /// Value of `suspend::count` when the poll started
+    suspends: usize,
+}
+
+/// Sets the number of nanoseconds a task may run without yielding before the executor is considered stalled
+pub fn set_stall_threshold(threshold: u64) {
+    STALL_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Gets the first poll that exceeded the stall threshold, if that task has not since yielded within it, or
+/// a poll on any vCPU that has been in progress for longer than the threshold
+pub fn stall() -> Option<Stall> {
+    if let Some(stall) = STALL.lock().clone() {
+        return Some(stall);
+    }
+
+    let now = get_monotonic_time();
+    let suspends = suspend::count();
+    let threshold = STALL_THRESHOLD.load(Ordering::Relaxed);
+
+    POLLING.iter().find_map(|polling| {
+        polling
+            .lock()
+            .as_ref()
+            // the time spent suspended does not count towards a poll spanning a suspend
+            .filter(|polling| polling.suspends == suspends)
+            .map(|polling| Stall {
+                id: polling.id,
+                name: polling.name,
+                duration: now.saturating_sub(polling.start),
+            })
+            .filter(|stall| stall.duration > threshold)
+    })
+}
+
+/// Information about a spawned task
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+    /// Unique identifier of the task
+    pub id: TaskId,
+    /// Name supplied when the task was spawned
+    pub name: &'static str,
+    /// Number of times the task has been polled
+    pub polls: u64,
+    /// Longest time spent in a single poll in nanoseconds
+    pub longest_poll: u64,
+}
+
+/// Gets information about all tasks that have not yet completed
+pub fn tasks() -> Vec<TaskInfo> {
+    TASKS.lock().values().cloned().collect()
+}
+
+/// Stops the executors of all vCPUs once their currently running tasks yield, cancelling all remaining tasks
+pub fn stop() {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+/// Makes executors running `run_until_stopped` return once their current polls complete, or lets them run again
+///
+/// Shared tasks they had queued are polled by the remaining executors.
+pub fn park(park: bool) {
+    PARK.store(park, Ordering::Relaxed);
+}
+
+/// Whether executors running `run_until_stopped` have been asked to return
+pub fn parked() -> bool {
+    PARK.load(Ordering::Relaxed)
+}
+
+/// Spawn a new task that may be polled by the executor of any vCPU
+pub fn spawn_shared(name: &'static str, fut: impl Future<Output = ()> + Send + 'static) {
+    let task = SharedTask::new(name, fut);
+
+    register(&task.0);
+    SHARED_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    SHARED[vcpu::id()].lock().push_back(task);
+}
+
+/// Records a newly spawned task
+fn register(task: &Task) {
+    TASKS.lock().insert(
+        task.id,
+        TaskInfo {
+            id: task.id,
+            name: task.name,
+            polls: 0,
+            longest_poll: 0,
+        },
+    );
+}
+
+/// Takes a shared task, first from the current vCPU's queue then from the others in turn
+fn steal() -> Option<SharedTask> {
+    let id = vcpu::id();
+
+    (0..MAX_VCPUS)
+        .map(|offset| (id + offset) % MAX_VCPUS)
+        .find_map(|id| SHARED[id].lock().pop_front())
+}
+
 /// Basic executor for async tasks
 pub struct Executor {
     tasks: VecDeque<Task>,
+    /// Alternates between local and shared tasks so neither starves the other
+    prefer_local: bool,
 }
 
 impl Executor {
@@ -21,25 +190,159 @@ impl Executor {
     pub fn new() -> Self {
         Self {
             tasks: VecDeque::new(),
+            prefer_local: false,
         }
     }
 
     /// Spawn a new task on the executor
-    pub fn spawn(&mut self, fut: impl Future<Output = ()> + 'static) {
-        self.tasks.push_back(Task::new(fut))
+    pub fn spawn(&mut self, name: &'static str, fut: impl Future<Output = ()> + 'static) {
+        let task = Task::new(name, fut);
+
+        register(&task);
+
+        self.tasks.push_back(task)
     }
 
-    /// Run the executor, polling tasks repeatedly
+    /// Run the executor, polling tasks repeatedly until all have completed or `stop` is called
+    ///
+    /// Shared tasks are polled alongside those spawned on the executor, and an executor with no tasks of
+    /// its own waits for shared tasks to steal until all have completed.
     pub fn run(&mut self) {
-        while let Some(mut task) = self.tasks.pop_front() {
-            let waker = new_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {}
-                Poll::Pending => self.tasks.push_back(task),
+        self.run_until(false)
+    }
+
+    /// Run the executor until `stop` or `park` is called, waiting for shared tasks to steal whenever it has none
+    ///
+    /// Used by secondary vCPUs, which have no tasks of their own and must keep polling shared tasks
+    /// spawned after they started.
+    pub fn run_until_stopped(&mut self) {
+        self.run_until(true)
+    }
+
+    fn run_until(&mut self, stopped: bool) {
+        loop {
+            if STOP.load(Ordering::Relaxed) {
+                self.cancel();
+                break;
+            }
+
+            if stopped && PARK.load(Ordering::Relaxed) {
+                break;
+            }
+
+            self.prefer_local = !self.prefer_local;
+
+            let local = if self.prefer_local {
+                self.tasks.pop_front()
+            } else {
+                None
+            };
+
+            if let Some(mut task) = local {
+                if poll(&mut task).is_pending() {
+                    self.tasks.push_back(task);
+                }
+            } else if let Some(mut task) = steal() {
+                if poll(&mut task.0).is_pending() {
+                    SHARED[vcpu::id()].lock().push_back(task);
+                } else {
+                    SHARED_COUNT.fetch_sub(1, Ordering::Relaxed);
+                }
+            } else if let Some(mut task) = self.tasks.pop_front() {
+                if poll(&mut task).is_pending() {
+                    self.tasks.push_back(task);
+                }
+            } else if !stopped && SHARED_COUNT.load(Ordering::Relaxed) == 0 {
+                break;
+            } else {
+                // remaining shared tasks are being polled by other vCPUs, or none have been spawned yet
+                schedule_operation(Command::Yield);
+            }
+        }
+    }
+
+    /// Drop all queued local and shared tasks without polling them again
+    fn cancel(&mut self) {
+        let mut tasks = TASKS.lock();
+
+        let shared = SHARED
+            .iter()
+            .flat_map(|queue| queue.lock().drain(..).collect::<VecDeque<_>>())
+            .map(|task| task.0);
+
+        for task in self.tasks.drain(..).chain(shared) {
+            log::debug!("cancelling task {} ({})", task.id, task.name);
+            tasks.remove(&task.id);
+        }
+    }
+}
+
+/// Polls a task once, recording its statistics and removing it from `TASKS` once complete
+fn poll(task: &mut Task) -> Poll<()> {
+    let waker = new_waker();
+    let mut context = Context::from_waker(&waker);
+
+    // attribute allocations made while polling to the task
+    let tag = stats::set_tag(task.name);
+    let suspends = suspend::count();
+    let start = get_monotonic_time();
+    *POLLING[vcpu::id()].lock() = Some(Polling {
+        id: task.id,
+        name: task.name,
+        start,
+        suspends,
+    });
+    let poll = task.poll(&mut context);
+    *POLLING[vcpu::id()].lock() = None;
+    let end = get_monotonic_time();
+    stats::set_tag(tag);
+
+    // a poll spanning a suspend includes the time spent suspended, and time may go backwards in a new domain
+    let duration = if suspend::count() == suspends {
+        end.saturating_sub(start)
+    } else {
+        0
+    };
+
+    let mut stall = STALL.lock();
+    if poll.is_pending() && duration > STALL_THRESHOLD.load(Ordering::Relaxed) {
+        if stall.is_none() {
+            warn!(
+                "task {} ({}) did not yield for {}ms",
+                task.id,
+                task.name,
+                duration / 1_000_000
+            );
+
+            *stall = Some(Stall {
+                id: task.id,
+                name: task.name,
+                duration,
+            });
+        }
+    } else if stall.as_ref().map(|stall| stall.id) == Some(task.id) {
+        // the task has yielded within the threshold again or completed, so is no longer stalling the executor
+        *stall = None;
+    }
+    drop(stall);
+
+    let mut tasks = TASKS.lock();
+    if let Some(info) = tasks.get_mut(&task.id) {
+        info.longest_poll = info.longest_poll.max(duration);
+    }
+
+    match poll {
+        Poll::Ready(()) => {
+            tasks.remove(&task.id);
+        }
+        Poll::Pending => {
+            if let Some(info) = tasks.get_mut(&task.id) {
+                info.polls += 1;
             }
         }
     }
+
+    poll
 }
 
 /// Create a new dummy RawWaker
@@ -3,21 +3,44 @@
 use {
     alloc::boxed::Box,
     core::{
+        fmt,
         future::Future,
         pin::Pin,
+        sync::atomic::{AtomicUsize, Ordering},
         task::{Context, Poll},
     },
 };
 
+/// Unique identifier of a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(usize);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 /// Wrapper around a pinned, boxed future
 pub struct Task {
+    pub id: TaskId,
+    pub name: &'static str,
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
     /// Create a new task from a future
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+    pub fn new(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
         Task {
+            id: TaskId::new(),
+            name,
             future: Box::pin(future),
         }
     }
@@ -26,3 +49,16 @@ impl Task {
         self.future.as_mut().poll(context)
     }
 }
+
+/// Task whose future is `Send`, so may be polled by any vCPU
+pub struct SharedTask(pub Task);
+
+// Required as `Task` erases the `Send` bound of the future, this is safe as `SharedTask::new` requires it
+unsafe impl Send for SharedTask {}
+
+impl SharedTask {
+    /// Create a new shared task from a future
+    pub fn new(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self(Task::new(name, future))
+    }
+}
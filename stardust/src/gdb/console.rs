@@ -0,0 +1,135 @@
+//! Secondary PV console used as the debugger connection
+//!
+//! The toolstack creates the console when the domain is configured with a channel, for example
+//! `channel = ['name=gdb,connection=socket,path=/tmp/stardust-gdb']`, whose socket GDB connects to
+//! with `target remote /tmp/stardust-gdb`. The ring is polled rather than driven by events, as it is
+//! only used while the kernel is stopped.
+
+use {
+    crate::mm::frame::{self, Frames, Owner},
+    alloc::format,
+    core::sync::atomic::{fence, Ordering},
+    gdb_protocol::Connection,
+    xen::{
+        events::event_channel_op,
+        grant_table,
+        memory::{MachineFrameNumber, VirtualAddress},
+        scheduler::{schedule_operation, Command},
+        xen_sys::{
+            domid_t, evtchn_alloc_unbound_t, evtchn_port_t, evtchn_send,
+            xenbus_state_XenbusStateConnected, xencons_interface, EVTCHNOP_alloc_unbound,
+            EVTCHNOP_send,
+        },
+        xenstore, DOMID_SELF,
+    },
+};
+
+/// XenStore directory of the console, index 0 is the primary console supplied in the start info page
+const PATH: &str = "device/console/1";
+
+/// Frontend of a secondary PV console
+pub struct Console {
+    interface: *mut xencons_interface,
+    event_channel: evtchn_port_t,
+    _ring: Frames,
+}
+
+// Required due to the raw mutable pointer to the ring not being Send, this is safe as the ring is owned by the console
+unsafe impl Send for Console {}
+
+impl Console {
+    /// Connects to the secondary console, returning `None` if the domain has none
+    pub fn connect() -> Option<Self> {
+        let backend = xenstore::read(format!("{}/backend-id\0", PATH))
+            .expect("Failed to read console backend-id")
+            .parse::<domid_t>()
+            .ok()?;
+
+        let ring = frame::allocate_zeroed(Owner::Debugger, 1)
+            .expect("Failed to allocate debugger console ring");
+        let interface = ring.as_mut_ptr::<xencons_interface>();
+
+        let reference = grant_table::grant_access(
+            backend,
+            MachineFrameNumber::from(VirtualAddress(interface as usize)),
+            false,
+        );
+
+        let mut op = evtchn_alloc_unbound_t {
+            dom: DOMID_SELF,
+            remote_dom: backend,
+            port: 0,
+        };
+        event_channel_op(EVTCHNOP_alloc_unbound, &mut op as *mut _ as u64);
+
+        xenstore::write(format!("{}/ring-ref\0", PATH), format!("{}", reference))
+            .expect("Failed to write console ring-ref");
+        xenstore::write(format!("{}/port\0", PATH), format!("{}", op.port))
+            .expect("Failed to write console port");
+        xenstore::write(
+            format!("{}/state\0", PATH),
+            format!("{}", xenbus_state_XenbusStateConnected),
+        )
+        .expect("Failed to write console state");
+
+        Some(Self {
+            interface,
+            event_channel: op.port,
+            _ring: ring,
+        })
+    }
+
+    fn notify(&self) {
+        let mut op = evtchn_send {
+            port: self.event_channel,
+        };
+        event_channel_op(EVTCHNOP_send, &mut op as *mut _ as u64);
+    }
+}
+
+impl Connection for Console {
+    fn read(&mut self) -> u8 {
+        // SAFETY: the ring is mapped for the lifetime of the console
+        let intf = unsafe { &mut *self.interface };
+
+        loop {
+            fence(Ordering::SeqCst);
+
+            if intf.in_cons != intf.in_prod {
+                let byte = intf.in_[(intf.in_cons & (intf.in_.len() as u32 - 1)) as usize] as u8;
+
+                fence(Ordering::SeqCst);
+                intf.in_cons += 1;
+                self.notify();
+
+                return byte;
+            }
+
+            schedule_operation(Command::Yield);
+        }
+    }
+
+    fn write(&mut self, mut bytes: &[u8]) {
+        // SAFETY: the ring is mapped for the lifetime of the console
+        let intf = unsafe { &mut *self.interface };
+
+        while !bytes.is_empty() {
+            fence(Ordering::SeqCst);
+
+            let mut prod = intf.out_prod;
+            while !bytes.is_empty() && ((prod - intf.out_cons) as usize) < intf.out.len() {
+                intf.out[(prod & (intf.out.len() as u32 - 1)) as usize] = bytes[0] as i8;
+                prod += 1;
+                bytes = &bytes[1..];
+            }
+
+            fence(Ordering::SeqCst);
+            intf.out_prod = prod;
+            self.notify();
+
+            if !bytes.is_empty() {
+                schedule_operation(Command::Yield);
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! GDB remote debugging
+//!
+//! With the `gdb` command line option a GDB remote serial protocol stub is connected to the secondary
+//! console and the kernel stops during boot for the debugger to attach. Breakpoint and debug traps
+//! are then reported to the debugger rather than being fatal, which can read and write registers and
+//! memory, insert software breakpoints, single-step and continue.
+//!
+//! Events are masked while stopped so no other kernel code runs on the stopped vCPU, although other
+//! vCPUs continue to run, and wait for the debugger to resume the kernel if they trap. The stub is not reachable over TCP as the network stack is driven by an
+//! executor task, which cannot run while the kernel is stopped.
+
+use {
+    crate::config,
+    console::Console,
+    core::{
+        arch::asm,
+        hint::spin_loop,
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    gdb_protocol::{Fault, Registers, Resume, Stub, Target, EFLAGS, RIP, SIGTRAP},
+    log::{info, warn},
+    spin::Mutex,
+    xen::{
+        events,
+        memory::{
+            update_va_mapping, virtual_memory::walk, PageEntry, TLBFlushFlags, VirtualAddress,
+        },
+        platform::consts::{PAGE_MASK, PAGE_RW},
+        scheduler::{schedule_operation, Command, ShutdownReason},
+        trap::TrapFrame,
+        vcpu,
+    },
+};
+
+mod console;
+
+/// Maximum number of software breakpoints inserted at once
+const MAX_BREAKPOINTS: usize = 32;
+
+/// x86 breakpoint instruction
+const INT3: u8 = 0xcc;
+
+/// Flag in rflags raising a debug trap after the next instruction
+const TRAP_FLAG: u64 = 1 << 8;
+
+/// Connected debugger, `None` if debugging is not enabled
+static DEBUGGER: Mutex<Option<Debugger>> = Mutex::new(None);
+
+/// ID of the vCPU stopped in the debugger, `NO_OWNER` if none is
+static DEBUGGER_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+/// Value of `DEBUGGER_OWNER` while no vCPU is stopped
+const NO_OWNER: usize = usize::MAX;
+
+/// Trap reported to the debugger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// `int3` instruction, either an inserted breakpoint or a call to `breakpoint`
+    Breakpoint,
+    /// Debug trap following a single step
+    Debug,
+}
+
+struct Debugger {
+    stub: Stub<Console>,
+    /// Addresses and original bytes of inserted breakpoints
+    breakpoints: [Option<(usize, u8)>; MAX_BREAKPOINTS],
+}
+
+/// Connects the debugger if enabled on the command line, then stops for it to attach
+pub fn init() {
+    if config::flag("gdb") != Some(true) {
+        return;
+    }
+
+    let console = match Console::connect() {
+        Some(console) => console,
+        None => {
+            warn!("gdb enabled but the domain has no secondary console");
+            return;
+        }
+    };
+
+    *DEBUGGER.lock() = Some(Debugger {
+        stub: Stub::new(console),
+        breakpoints: [None; MAX_BREAKPOINTS],
+    });
+
+    info!("waiting for debugger on secondary console");
+    breakpoint();
+}
+
+/// Stops in the debugger, does nothing if one is not connected
+pub fn breakpoint() {
+    if DEBUGGER.lock().is_some() {
+        unsafe { asm!("int3") };
+    }
+}
+
+/// Reports a trap to the debugger and waits until it resumes the kernel
+///
+/// Returns `false` if no debugger is connected, in which case the trap is fatal.
+pub fn trap(frame: &mut TrapFrame, trap: Trap) -> bool {
+    let id = vcpu::id();
+
+    loop {
+        if let Some(mut debugger) = DEBUGGER.try_lock() {
+            DEBUGGER_OWNER.store(id, Ordering::Relaxed);
+
+            let handled = match debugger.as_mut() {
+                Some(debugger) => stop(debugger, frame, trap),
+                None => false,
+            };
+
+            DEBUGGER_OWNER.store(NO_OWNER, Ordering::Relaxed);
+            return handled;
+        }
+
+        // a trap inside the stub itself cannot be debugged
+        if DEBUGGER_OWNER.load(Ordering::Relaxed) == id {
+            return false;
+        }
+
+        // another vCPU is stopped in the debugger, which releases it once resumed
+        spin_loop();
+    }
+}
+
+/// Stops in the debugger until it resumes the kernel
+fn stop(debugger: &mut Debugger, frame: &mut TrapFrame, trap: Trap) -> bool {
+    let Debugger { stub, breakpoints } = debugger;
+
+    // the breakpoint was removed while waiting for another vCPU to resume, run the original instruction
+    if trap == Trap::Breakpoint && !matches!(peek(frame.rip as usize - 1), Ok(INT3)) {
+        frame.rip -= 1;
+        return true;
+    }
+
+    // int3 leaves rip after the instruction, report an inserted breakpoint at its own address
+    if trap == Trap::Breakpoint
+        && breakpoints
+            .iter()
+            .flatten()
+            .any(|(address, _)| *address as u64 == frame.rip - 1)
+    {
+        frame.rip -= 1;
+    }
+
+    frame.rflags &= !TRAP_FLAG;
+
+    let mask = events::mask_upcalls();
+
+    let resume = stub.stop(
+        &mut Kernel {
+            frame: &mut *frame,
+            breakpoints: &mut *breakpoints,
+        },
+        SIGTRAP,
+    );
+
+    match resume {
+        Resume::Continue => {}
+        Resume::Step => frame.rflags |= TRAP_FLAG,
+        Resume::Detach => {
+            for (address, original) in breakpoints.iter_mut().filter_map(Option::take) {
+                if poke(address, original).is_err() {
+                    warn!("failed to remove breakpoint at {:#x}", address);
+                }
+            }
+        }
+        Resume::Kill => schedule_operation(Command::Shutdown(ShutdownReason::Poweroff)),
+    }
+
+    events::restore_upcalls(mask);
+
+    true
+}
+
+/// Kernel stopped in a trap handler
+struct Kernel<'a> {
+    frame: &'a mut TrapFrame,
+    breakpoints: &'a mut [Option<(usize, u8)>; MAX_BREAKPOINTS],
+}
+
+impl Target for Kernel<'_> {
+    fn registers(&self) -> Registers {
+        let f = &self.frame;
+
+        Registers([
+            f.rax, f.rbx, f.rcx, f.rdx, f.rsi, f.rdi, f.rbp, f.rsp, f.r8, f.r9, f.r10, f.r11,
+            f.r12, f.r13, f.r14, f.r15, f.rip, f.rflags, f.cs, f.ss, 0, 0, 0, 0,
+        ])
+    }
+
+    fn set_registers(&mut self, registers: &Registers) {
+        let f = &mut self.frame;
+        let r = &registers.0;
+
+        // segment registers are fixed by the hypervisor so are not written
+        f.rax = r[0];
+        f.rbx = r[1];
+        f.rcx = r[2];
+        f.rdx = r[3];
+        f.rsi = r[4];
+        f.rdi = r[5];
+        f.rbp = r[6];
+        f.rsp = r[7];
+        f.r8 = r[8];
+        f.r9 = r[9];
+        f.r10 = r[10];
+        f.r11 = r[11];
+        f.r12 = r[12];
+        f.r13 = r[13];
+        f.r14 = r[14];
+        f.r15 = r[15];
+        f.rip = r[RIP];
+        f.rflags = r[EFLAGS];
+    }
+
+    fn read_memory(&mut self, address: usize, data: &mut [u8]) -> Result<(), Fault> {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = peek(address.checked_add(i).ok_or(Fault)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> Result<(), Fault> {
+        for (i, byte) in data.iter().enumerate() {
+            poke(address.checked_add(i).ok_or(Fault)?, *byte)?;
+        }
+
+        Ok(())
+    }
+
+    fn insert_breakpoint(&mut self, address: usize) -> Result<(), Fault> {
+        if self
+            .breakpoints
+            .iter()
+            .flatten()
+            .any(|(a, _)| *a == address)
+        {
+            return Ok(());
+        }
+
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(Fault)?;
+
+        let original = peek(address)?;
+        poke(address, INT3)?;
+        *slot = Some((address, original));
+
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, address: usize) -> Result<(), Fault> {
+        let slot = self
+            .breakpoints
+            .iter_mut()
+            .find(|slot| matches!(slot, Some((a, _)) if *a == address));
+
+        if let Some(slot) = slot {
+            let (_, original) = slot.take().unwrap();
+            poke(address, original)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a byte, failing if it is not mapped
+fn peek(address: usize) -> Result<u8, Fault> {
+    walk(VirtualAddress(address)).ok_or(Fault)?;
+
+    Ok(unsafe { ptr::read_volatile(address as *const u8) })
+}
+
+/// Writes a byte, temporarily making read-only pages such as kernel text writable
+fn poke(address: usize, value: u8) -> Result<(), Fault> {
+    let entry = walk(VirtualAddress(address)).ok_or(Fault)?;
+    let page = VirtualAddress(address & PAGE_MASK);
+    let readonly = entry.0 & PAGE_RW == 0;
+
+    if readonly {
+        update_va_mapping(page, PageEntry(entry.0 | PAGE_RW), TLBFlushFlags::FLUSH)
+            .map_err(|_| Fault)?;
+    }
+
+    unsafe { ptr::write_volatile(address as *mut u8, value) };
+
+    if readonly {
+        update_va_mapping(page, entry, TLBFlushFlags::FLUSH).map_err(|_| Fault)?;
+    }
+
+    Ok(())
+}
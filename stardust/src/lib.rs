@@ -4,6 +4,7 @@
 #![deny(missing_docs)]
 #![feature(alloc_error_handler)]
 #![feature(core_intrinsics)]
+#![feature(panic_info_message)]
 
 extern crate alloc;
 
@@ -12,22 +13,33 @@ use {
     executor::Executor,
     log::{debug, error},
     xen::{
-        console::Writer,
-        events, grant_table, init_info, println,
+        console::{Reader, Writer},
+        emergency_println, events, grant_table, init_info,
+        platform::{backtrace::Backtrace, fpu},
+        println,
         scheduler::{schedule_operation, Command, ShutdownReason},
         sections::{edata, end, erodata, etext, text_start},
-        time,
+        suspend, time, version,
         xen_sys::start_info_t,
         xenbus, xenstore,
     },
 };
 
+mod backtrace;
+mod config;
+mod control;
+mod crash;
 mod executor;
+mod gdb;
 mod logger;
 mod mm;
 mod net;
+mod smp;
 mod trap;
+mod watchdog;
 
+#[cfg(feature = "shell")]
+mod shell;
 #[cfg(feature = "test")]
 mod test;
 
@@ -52,24 +64,38 @@ pub fn launch(start_info: *mut start_info_t) {
     print_start_info(start_info);
 
     trap::init();
+    fpu::init();
     events::init();
+    Reader::init(start_info);
     time::init();
     mm::init(start_info);
+    suspend::init();
     grant_table::init();
     xenstore::init();
     xenbus::init();
+    gdb::init();
+    smp::init();
 
     #[cfg(feature = "test")]
     test::tests();
 
     let mut executor = Executor::new();
-    //executor.spawn(xenbus::task());
-    executor.spawn(net::server());
+    //executor.spawn("xenbus", xenbus::task());
+    executor.spawn("control", control::task());
+    executor.spawn("net", net::server());
+    executor::spawn_shared("watchdog", watchdog::task());
+    executor.spawn("balloon", mm::balloon::task());
+    #[cfg(feature = "shell")]
+    executor.spawn("shell", shell::task());
     executor.run();
 
-    // if run() terminates then all tasks have completed, exit cleanly
+    // if run() terminates then all tasks have completed or a shutdown was requested, exit cleanly
+    mm::stats::dump();
+    #[cfg(feature = "alloc-debug")]
+    mm::stats::report_outstanding();
+
     Writer::flush();
-    schedule_operation(Command::Shutdown(ShutdownReason::Poweroff));
+    schedule_operation(Command::Shutdown(control::request().reason()));
 }
 
 fn print_start_info(start_info: &start_info_t) {
@@ -79,6 +105,11 @@ fn print_start_info(start_info: &start_info_t) {
     .unwrap();
 
     debug!("   platform: {}", magic_str);
+    debug!(
+        "    version: {}{}",
+        version::version(),
+        version::extraversion().unwrap_or_default()
+    );
     debug!("  domain ID: {}", xen::xenstore::domain_id());
     debug!("   nr_pages: {}", start_info.nr_pages);
     debug!("shared_info: {:#X}", start_info.shared_info);
@@ -96,8 +127,23 @@ fn print_start_info(start_info: &start_info_t) {
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+    // report directly to the hypervisor as well in case the console ring is wedged
+    if !cfg!(feature = "console-io") {
+        emergency_println!("{}", info);
+    }
+
     error!("{}", info);
 
+    backtrace::log(Backtrace::current());
+
+    mm::stats::dump();
+
+    if let Some(true) = config::flag("log.dump_on_panic") {
+        logger::dump();
+    }
+
+    crash::write(info);
+
     Writer::flush();
 
     schedule_operation(Command::Shutdown(ShutdownReason::Crash));
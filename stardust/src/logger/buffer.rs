@@ -0,0 +1,259 @@
+//! In-memory kernel log buffer
+//!
+//! Bounded ring of log records, the equivalent of `dmesg`. Once full, the oldest records are
+//! overwritten. Records are stored unformatted so they can be rendered for the console, dumped on
+//! panic or shipped elsewhere.
+
+use {
+    core::{fmt, str},
+    log::Level,
+    xen::sync::IrqSafeMutex,
+};
+
+/// Size of the log buffer in bytes
+pub const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Maximum length of a record target in bytes, longer targets are truncated
+pub const MAX_TARGET_LEN: usize = 64;
+
+/// Maximum length of a record message in bytes, longer messages are truncated
+pub const MAX_MESSAGE_LEN: usize = 512;
+
+/// Length of a record header: timestamp (8), level (1), target length (1) and message length (2)
+const HEADER_LEN: usize = 12;
+
+/// Global log buffer, records are logged from event handlers
+pub static BUFFER: IrqSafeMutex<LogBuffer> = IrqSafeMutex::new(LogBuffer::new());
+
+/// Ring buffer of log records
+pub struct LogBuffer {
+    data: [u8; BUFFER_SIZE],
+    /// Offset of the oldest record, only ever increases
+    head: usize,
+    /// Offset after the newest record, only ever increases
+    tail: usize,
+    /// Sequence number of the oldest record
+    first_sequence: u64,
+    /// Sequence number given to the next record
+    next_sequence: u64,
+}
+
+impl LogBuffer {
+    /// Create a new empty log buffer
+    pub const fn new() -> Self {
+        Self {
+            data: [0; BUFFER_SIZE],
+            head: 0,
+            tail: 0,
+            first_sequence: 0,
+            next_sequence: 0,
+        }
+    }
+
+    /// Sequence number of the oldest record still in the buffer
+    pub fn first_sequence(&self) -> u64 {
+        self.first_sequence
+    }
+
+    /// Sequence number that will be given to the next record
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Append a record, evicting the oldest records if there is insufficient space
+    pub fn push(&mut self, timestamp: u64, level: Level, target: &str, message: &str) {
+        let target = truncate(target, MAX_TARGET_LEN);
+        let message = truncate(message, MAX_MESSAGE_LEN);
+
+        let header = Header {
+            timestamp,
+            level,
+            target_len: target.len(),
+            message_len: message.len(),
+        };
+
+        while self.tail - self.head + header.record_len() > BUFFER_SIZE {
+            let oldest = self.header(self.head);
+            self.head += oldest.record_len();
+            self.first_sequence += 1;
+        }
+
+        self.write(&header.to_bytes());
+        self.write(target.as_bytes());
+        self.write(message.as_bytes());
+
+        self.next_sequence += 1;
+    }
+
+    /// Read a copy of the oldest record with a sequence number greater than or equal to `sequence`
+    ///
+    /// If records have been overwritten the returned entry's sequence number will be greater than the one requested.
+    pub fn read(&self, sequence: u64) -> Option<Entry> {
+        let mut offset = self.head;
+        let mut current = self.first_sequence;
+
+        while current < sequence && offset != self.tail {
+            offset += self.header(offset).record_len();
+            current += 1;
+        }
+
+        if offset == self.tail {
+            return None;
+        }
+
+        let header = self.header(offset);
+
+        let mut entry = Entry {
+            sequence: current,
+            timestamp: header.timestamp,
+            level: header.level,
+            target: [0; MAX_TARGET_LEN],
+            target_len: header.target_len,
+            message: [0; MAX_MESSAGE_LEN],
+            message_len: header.message_len,
+        };
+
+        self.copy(offset + HEADER_LEN, &mut entry.target[..header.target_len]);
+        self.copy(
+            offset + HEADER_LEN + header.target_len,
+            &mut entry.message[..header.message_len],
+        );
+
+        Some(entry)
+    }
+
+    fn header(&self, offset: usize) -> Header {
+        let mut bytes = [0; HEADER_LEN];
+        self.copy(offset, &mut bytes);
+        Header::from_bytes(&bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.data[self.tail % BUFFER_SIZE] = byte;
+            self.tail += 1;
+        }
+    }
+
+    fn copy(&self, offset: usize, destination: &mut [u8]) {
+        for (i, byte) in destination.iter_mut().enumerate() {
+            *byte = self.data[(offset + i) % BUFFER_SIZE];
+        }
+    }
+}
+
+/// Copy of a single record read from the log buffer
+pub struct Entry {
+    /// Sequence number of the record
+    pub sequence: u64,
+    /// Nanoseconds since boot at which the record was logged
+    pub timestamp: u64,
+    /// Level of the record
+    pub level: Level,
+    target: [u8; MAX_TARGET_LEN],
+    target_len: usize,
+    message: [u8; MAX_MESSAGE_LEN],
+    message_len: usize,
+}
+
+impl Entry {
+    /// Target of the record, usually the module path
+    pub fn target(&self) -> &str {
+        str::from_utf8(&self.target[..self.target_len]).unwrap_or("")
+    }
+
+    /// Formatted message of the record
+    pub fn message(&self) -> &str {
+        str::from_utf8(&self.message[..self.message_len]).unwrap_or("")
+    }
+}
+
+/// Fixed size buffer for formatting a record message without allocating
+///
+/// Output beyond `MAX_MESSAGE_LEN` bytes is silently discarded.
+pub struct Message {
+    buf: [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Message {
+    /// Create a new empty message
+    pub fn new() -> Self {
+        Self {
+            buf: [0; MAX_MESSAGE_LEN],
+            len: 0,
+        }
+    }
+
+    /// Contents of the message
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Message {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let s = truncate(s, MAX_MESSAGE_LEN - self.len);
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Record header as stored in the log buffer
+struct Header {
+    timestamp: u64,
+    level: Level,
+    target_len: usize,
+    message_len: usize,
+}
+
+impl Header {
+    /// Length of the header and record contents
+    fn record_len(&self) -> usize {
+        HEADER_LEN + self.target_len + self.message_len
+    }
+
+    fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8] = self.level as u8;
+        bytes[9] = self.target_len as u8;
+        bytes[10..].copy_from_slice(&(self.message_len as u16).to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Self {
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[..8]);
+
+        let level = match bytes[8] {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        };
+
+        Self {
+            timestamp: u64::from_le_bytes(timestamp),
+            level,
+            target_len: usize::from(bytes[9]),
+            message_len: usize::from(u16::from_le_bytes([bytes[10], bytes[11]])),
+        }
+    }
+}
+
+/// Truncate a string to at most `max` bytes without splitting a character
+pub fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    &s[..end]
+}
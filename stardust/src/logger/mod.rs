@@ -0,0 +1,148 @@
+//! Logger implementation
+//!
+//! Records are printed to the Xen console prefixed with the time since boot and their target, and
+//! are retained in the in-memory log buffer.
+
+use {
+    crate::config,
+    core::{
+        fmt::{self, Write},
+        hint::spin_loop,
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    log::{Level, LevelFilter, Log, Metadata, Record},
+    xen::{console::Writer, println, time::get_monotonic_time, vcpu},
+};
+
+pub mod buffer;
+
+static LOGGER: Logger = Logger;
+
+/// Whether ANSI colour codes are included in console output
+static COLOUR: AtomicBool = AtomicBool::new(true);
+
+/// ID of the vCPU pushing a record to the log buffer, `NO_OWNER` if none is
+static BUFFER_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+/// Value of `BUFFER_OWNER` while no vCPU is pushing a record
+const NO_OWNER: usize = usize::MAX;
+
+/// Initialise logger using the xen::console backend
+///
+/// Colour output can be disabled for log collectors with the `log.colour=off` command line option.
+pub fn init() {
+    if let Some(colour) = config::flag("log.colour") {
+        set_colour(colour);
+    }
+
+    log::set_logger(&LOGGER)
+        .map(|()| log::set_max_level(LevelFilter::Trace))
+        .expect("Failed to set logger");
+}
+
+/// Enable or disable ANSI colour codes in console output
+pub fn set_colour(enabled: bool) {
+    COLOUR.store(enabled, Ordering::Relaxed);
+}
+
+/// Print the contents of the log buffer to the console
+///
+/// Does nothing if the log buffer is locked, for example when panicking while logging.
+pub fn dump() {
+    let buffer = match buffer::BUFFER.try_lock() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
+    println!("---- log buffer ----");
+
+    let mut sequence = buffer.first_sequence();
+    while let Some(entry) = buffer.read(sequence) {
+        print_record(
+            entry.timestamp,
+            entry.level,
+            entry.target(),
+            &format_args!("{}", entry.message()),
+        );
+        sequence = entry.sequence + 1;
+    }
+
+    println!("---- end of log buffer ----");
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let timestamp = get_monotonic_time();
+
+        let mut message = buffer::Message::new();
+        // messages that do not fit are truncated rather than failing
+        let _ = write!(message, "{}", record.args());
+
+        buffer(timestamp, record, message.as_str());
+
+        print_record(timestamp, record.level(), record.target(), record.args());
+    }
+
+    fn flush(&self) {
+        Writer::flush();
+    }
+}
+
+/// Pushes a record to the log buffer, waiting for other vCPUs to finish pushing theirs
+fn buffer(timestamp: u64, record: &Record, message: &str) {
+    let id = vcpu::id();
+
+    loop {
+        if let Some(mut buffer) = buffer::BUFFER.try_lock() {
+            BUFFER_OWNER.store(id, Ordering::Relaxed);
+            buffer.push(timestamp, record.level(), record.target(), message);
+            BUFFER_OWNER.store(NO_OWNER, Ordering::Relaxed);
+            return;
+        }
+
+        // a trap or panic interrupted a push on this vCPU, the record is not buffered rather than deadlocking
+        if BUFFER_OWNER.load(Ordering::Relaxed) == id {
+            return;
+        }
+
+        // held by another vCPU, which will release it once its push completes
+        spin_loop();
+    }
+}
+
+fn print_record(timestamp: u64, level: Level, target: &str, args: &fmt::Arguments) {
+    println!(
+        "[{:>5}.{:06}] {} {}: {}",
+        timestamp / 1_000_000_000,
+        (timestamp % 1_000_000_000) / 1_000,
+        format_level(level),
+        target,
+        args
+    );
+}
+
+fn format_level(level: Level) -> &'static str {
+    if COLOUR.load(Ordering::Relaxed) {
+        match level {
+            Level::Trace => "\x1b[0;35mTRACE\x1b[0m",
+            Level::Debug => "\x1b[0;34mDEBUG\x1b[0m",
+            Level::Info => "\x1b[0;32mINFO \x1b[0m",
+            Level::Warn => "\x1b[0;33mWARN \x1b[0m",
+            Level::Error => "\x1b[0;31mERROR\x1b[0m",
+        }
+    } else {
+        match level {
+            Level::Trace => "TRACE",
+            Level::Debug => "DEBUG",
+            Level::Info => "INFO ",
+            Level::Warn => "WARN ",
+            Level::Error => "ERROR",
+        }
+    }
+}
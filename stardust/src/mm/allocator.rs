@@ -1,13 +1,164 @@
 //! Kernel memory allocator
+//!
+//! The heap starts with a small region and grows on demand with frames taken from the frame allocator,
+//! reclaiming ballooned pages from the hypervisor if none are free. Registered out-of-memory handlers
+//! are called to shed caches before an allocation is allowed to fail.
 
 use {
-    buddy_system_allocator::LockedHeap,
-    log::{error, info},
-    xen::memory::VirtualAddress,
+    crate::mm::{
+        balloon,
+        frame::{self, Frames, Owner},
+        stats,
+    },
+    alloc::{alloc::Layout, vec::Vec},
+    core::{
+        alloc::GlobalAlloc,
+        cell::Cell,
+        cmp::max,
+        ptr::{self, NonNull},
+    },
+    log::{error, info, warn},
+    spin::{Mutex, MutexGuard},
+    xen::{memory::VirtualAddress, percpu, platform::consts::PAGE_SIZE},
 };
 
 #[global_allocator]
-pub static ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+pub static ALLOCATOR: Heap = Heap::empty();
+
+/// Number of frames the heap is initialised with
+pub const INITIAL_FRAMES: usize = 1024;
+
+/// Minimum number of frames added to the heap when it grows, if enough are free
+const GROW_FRAMES: usize = 256;
+
+/// Handlers called to free memory when the heap cannot grow
+static OOM_HANDLERS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+percpu! {
+    /// Set while the heap is growing on this vCPU, allocations made in the process must not grow it again
+    static GROWING: Cell<bool> = Cell::new(false);
+}
+
+/// Growable kernel heap
+pub struct Heap(Mutex<buddy_system_allocator::Heap<32>>);
+
+impl Heap {
+    const fn empty() -> Self {
+        Self(Mutex::new(buddy_system_allocator::Heap::new()))
+    }
+
+    /// Lock the underlying buddy allocator
+    pub fn lock(&self) -> MutexGuard<'_, buddy_system_allocator::Heap<32>> {
+        self.0.lock()
+    }
+
+    /// Add at least enough frames to the heap to satisfy `layout`, returning whether it grew
+    fn grow(&self, layout: &Layout) -> bool {
+        let growing = GROWING.get();
+        if growing.replace(true) {
+            return false;
+        }
+
+        // buddy blocks are aligned to their size, so the block satisfying the layout must be a single
+        // run of frames aligned to it
+        let size = max(
+            max(layout.size(), layout.align()).next_power_of_two(),
+            PAGE_SIZE,
+        );
+        let count = size / PAGE_SIZE;
+
+        let block = frame::allocate_aligned(Owner::Heap, count, size).or_else(|| {
+            balloon::reclaim(count);
+            frame::allocate_aligned(Owner::Heap, count, size)
+        });
+
+        if let Some(block) = block {
+            self.add_frames(block);
+
+            // top up small growths from whatever free runs remain, so the heap does not grow a page at a time
+            let mut added = block.count();
+            while added < GROW_FRAMES {
+                match frame::allocate_run(Owner::Heap, GROW_FRAMES - added) {
+                    Some(frames) => {
+                        self.add_frames(frames);
+                        added += frames.count();
+                    }
+                    None => break,
+                }
+            }
+        }
+
+        growing.set(false);
+
+        block.is_some()
+    }
+
+    /// Add a contiguous run of frames to the heap
+    fn add_frames(&self, frames: Frames) {
+        let start = frames.address().0;
+        unsafe {
+            self.lock()
+                .add_to_heap(start, start + frames.count() * PAGE_SIZE)
+        };
+    }
+
+    fn try_alloc(&self, layout: Layout) -> Option<NonNull<u8>> {
+        self.lock().alloc(layout).ok()
+    }
+
+    /// Allocate from the heap, growing it or calling out-of-memory handlers if required
+    fn allocate(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = self.try_alloc(layout) {
+            return ptr.as_ptr();
+        }
+
+        if self.grow(&layout) {
+            if let Some(ptr) = self.try_alloc(layout) {
+                return ptr.as_ptr();
+            }
+        }
+
+        // only handle out of memory at the outermost allocation
+        if GROWING.get().get() {
+            return ptr::null_mut();
+        }
+
+        warn!("out of memory allocating {:?}, shedding caches", layout);
+
+        // a handler that itself runs out of memory must not deadlock on the handler list
+        if let Some(handlers) = OOM_HANDLERS.try_lock() {
+            for handler in handlers.iter() {
+                handler();
+            }
+        }
+
+        self.try_alloc(layout)
+            .or_else(|| {
+                self.grow(&layout);
+                self.try_alloc(layout)
+            })
+            .map(NonNull::as_ptr)
+            .unwrap_or_else(ptr::null_mut)
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocate(stats::layout(layout));
+
+        if ptr.is_null() {
+            return ptr;
+        }
+
+        stats::on_alloc(ptr, layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let ptr = stats::on_dealloc(ptr, layout);
+        self.lock()
+            .dealloc(NonNull::new_unchecked(ptr), stats::layout(layout))
+    }
+}
 
 /// Initialize allocator
 pub unsafe fn init(heap_start: VirtualAddress, heap_size: usize) {
@@ -19,8 +170,16 @@ pub unsafe fn init(heap_start: VirtualAddress, heap_size: usize) {
     ALLOCATOR.lock().init(heap_start.0, heap_size);
 }
 
+/// Registers a handler called to free memory before an allocation fails
+///
+/// Handlers are called with the handler list locked, so must not register further handlers.
+pub fn register_oom_handler(handler: fn()) {
+    OOM_HANDLERS.lock().push(handler);
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     error!("ALLOCATOR: {:?}", ALLOCATOR.lock());
+    stats::dump();
     panic!("allocation error: {:?}", layout);
 }
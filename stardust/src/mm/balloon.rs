@@ -0,0 +1,235 @@
+//! Balloon driver
+//!
+//! Watches `memory/target` and returns pages to the hypervisor or reclaims them so that the domain's
+//! reservation follows the target set by the toolstack. Ballooned pages remain allocated from the
+//! frame allocator until they are reclaimed, so the domain cannot grow beyond its initial size. If the
+//! hypervisor fails an operation the affected pages are kept where they were, and the reservation is
+//! adjusted again on the next change to the target.
+
+use {
+    crate::mm::frame::{self, Frames, Owner},
+    alloc::vec::Vec,
+    core::cmp::min,
+    log::{error, info, warn},
+    spin::Mutex,
+    xen::{
+        memory::{
+            decrease_reservation, get_current_pages, hypervisor_mmu_update, increase_reservation,
+            machine_to_phys_update, set_phys_to_machine, update_va_mapping, MachineFrameNumber,
+            PageEntry, TLBFlushFlags, INVALID_MFN,
+        },
+        multicall::Multicall,
+        platform::consts::{L1_PROT_NX, PAGE_SHIFT, PAGE_SIZE},
+        xen_sys::mmu_update_t,
+        xenbus, xenstore,
+    },
+};
+
+/// Watch token for `memory/target`
+const TARGET_TOKEN: &str = "memory/target\0";
+
+/// Maximum number of pages exchanged with the hypervisor in a single hypercall
+const BATCH_SIZE: usize = 256;
+
+/// Free frames that are never given back to the hypervisor
+const MIN_FREE_FRAMES: usize = 1024;
+
+/// Page frames currently returned to the hypervisor
+static BALLOONED: Mutex<Vec<Frames>> = Mutex::new(Vec::new());
+
+/// Gets the number of pages currently returned to the hypervisor
+pub fn ballooned() -> usize {
+    BALLOONED.lock().len()
+}
+
+/// Balloon task, adjusts the reservation whenever `memory/target` changes
+pub async fn task() {
+    xenbus::watch(TARGET_TOKEN, TARGET_TOKEN).await;
+
+    loop {
+        xenbus::watch_event(TARGET_TOKEN).await;
+
+        // target is supplied in KiB
+        let target = xenstore::read(TARGET_TOKEN).expect("Failed to read memory target");
+        match target.parse::<usize>() {
+            Ok(target) => set_target(target * 1024 / PAGE_SIZE),
+            Err(e) => warn!("invalid memory target: {}", e),
+        }
+    }
+}
+
+/// Adjusts the reservation of the domain towards `target` pages
+pub fn set_target(target: usize) {
+    let current = match get_current_pages() {
+        Ok(current) => current,
+        Err(e) => {
+            warn!("failed to get current reservation: {}", e);
+            return;
+        }
+    };
+
+    if target < current {
+        let released = decrease(current - target);
+        info!("released {} of {} pages", released, current - target);
+    } else if target > current {
+        let reclaimed = increase(&mut BALLOONED.lock(), target - current);
+        info!("reclaimed {} of {} pages", reclaimed, target - current);
+    }
+}
+
+/// Reclaims up to `count` ballooned pages for the heap to grow into, returning the number reclaimed
+///
+/// Does nothing if the balloon is already being adjusted, as the heap may be grown from within it.
+pub fn reclaim(count: usize) -> usize {
+    match BALLOONED.try_lock() {
+        Some(mut ballooned) => increase(&mut ballooned, count),
+        None => 0,
+    }
+}
+
+/// Returns up to `count` pages taken from the frame allocator to the hypervisor, returning the number released
+fn decrease(count: usize) -> usize {
+    let mut released = 0;
+
+    while released < count {
+        let batch = min(BATCH_SIZE, count - released);
+        let mut frames = Vec::with_capacity(batch);
+        let mut mfns = Vec::with_capacity(batch);
+
+        while frames.len() < batch && frame::free_frames() > MIN_FREE_FRAMES {
+            let page = match frame::allocate(Owner::Balloon, 1) {
+                Some(page) => page,
+                None => break,
+            };
+
+            frames.push(page);
+            mfns.push(MachineFrameNumber::from(page.start()));
+        }
+
+        if frames.is_empty() {
+            warn!("no free memory left to release");
+            break;
+        }
+
+        // unmap the whole batch in a single hypercall
+        let mut unmap = Multicall::new();
+        for page in &frames {
+            unmap.update_va_mapping(page.address(), PageEntry(0), TLBFlushFlags::INVLPG);
+        }
+        let unmapped = unmap
+            .submit()
+            .and_then(|results| results.into_iter().collect::<Result<Vec<_>, _>>());
+
+        if let Err(e) = unmapped {
+            warn!("failed to unmap ballooned pages: {}", e);
+
+            // pages that were unmapped are mapped again, remapping the others is harmless
+            for (page, mfn) in frames.iter().zip(&mfns) {
+                unsafe { map(*page, *mfn) };
+            }
+            break;
+        }
+
+        for page in &frames {
+            unsafe { set_phys_to_machine(page.start(), INVALID_MFN) };
+        }
+
+        let n = decrease_reservation(&mfns).unwrap_or_else(|e| {
+            warn!("failed to decrease reservation: {}", e);
+            0
+        });
+
+        // restore any frames the hypervisor did not take
+        for (page, mfn) in frames[n..].iter().zip(&mfns[n..]) {
+            unsafe { map(*page, *mfn) };
+        }
+
+        BALLOONED.lock().extend_from_slice(&frames[..n]);
+        released += n;
+
+        if n < frames.len() {
+            break;
+        }
+    }
+
+    released
+}
+
+/// Reclaims up to `count` of the `ballooned` pages from the hypervisor and frees them to the frame allocator, returning the number reclaimed
+///
+/// Called while the heap is growing, so must not allocate. `ballooned` is only shortened, and stays
+/// locked throughout so that no other vCPU can grow it meanwhile.
+fn increase(ballooned: &mut Vec<Frames>, count: usize) -> usize {
+    let mut reclaimed = 0;
+
+    while reclaimed < count {
+        let batch = min(min(BATCH_SIZE, count - reclaimed), ballooned.len());
+
+        if batch == 0 {
+            warn!("cannot grow beyond the initial reservation");
+            break;
+        }
+
+        // reclaim the most recently ballooned pages
+        let at = ballooned.len() - batch;
+
+        let mut mfns = [INVALID_MFN; BATCH_SIZE];
+        let n = match increase_reservation(&mut mfns[..batch]) {
+            Ok(n) => n,
+            Err(e) => {
+                warn!("failed to increase reservation: {}", e);
+                break;
+            }
+        };
+
+        let backed = ballooned[at..].iter().zip(&mfns[..n]);
+
+        let mut machine_to_phys = [mmu_update_t { ptr: 0, val: 0 }; BATCH_SIZE];
+        for (update, (page, mfn)) in machine_to_phys.iter_mut().zip(backed.clone()) {
+            *update = machine_to_phys_update(*mfn, page.start());
+        }
+
+        if let Err(e) = hypervisor_mmu_update(&machine_to_phys[..n]) {
+            warn!("failed to update machine-to-physical table: {}", e);
+
+            // the pages stay ballooned, so the new frames are given straight back
+            if let Err(e) = decrease_reservation(&mfns[..n]) {
+                error!("failed to return {} reclaimed frames: {}", n, e);
+            }
+            break;
+        }
+
+        // mapped one at a time, as a multicall would allocate
+        for (page, mfn) in backed {
+            unsafe { map(*page, *mfn) };
+        }
+
+        // keep any frames the hypervisor could not back
+        ballooned.drain(at..at + n);
+        reclaimed += n;
+
+        if n < batch {
+            break;
+        }
+    }
+
+    reclaimed
+}
+
+/// Backs a ballooned page frame with `mfn`, maps it and frees it to the frame allocator
+///
+/// A page that cannot be mapped is leaked rather than freed.
+unsafe fn map(page: Frames, mfn: MachineFrameNumber) {
+    set_phys_to_machine(page.start(), mfn);
+
+    if let Err(e) = update_va_mapping(
+        page.address(),
+        PageEntry(mfn.0 << PAGE_SHIFT | L1_PROT_NX),
+        TLBFlushFlags::INVLPG,
+    ) {
+        error!("failed to map page {:#x}: {}", page.address().0, e);
+        return;
+    }
+
+    frame::free(Owner::Balloon, page);
+}
@@ -0,0 +1,351 @@
+//! Physical page frame allocator
+//!
+//! Hands out page frames from the memory mapped at boot, recording the subsystem that owns each one.
+//! The owner of every frame is stored in a table occupying the first frames of the region, so the
+//! allocator does not depend on the heap, which is itself carved out of it.
+
+use {
+    core::{cmp::max, fmt, ptr, slice},
+    spin::Mutex,
+    xen::{
+        memory::{MachineFrameNumber, PageFrameNumber, VirtualAddress},
+        platform::consts::PAGE_SIZE,
+    },
+};
+
+/// Global frame allocator
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// Subsystem owning allocated frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Owner {
+    /// Table of frame owners used by the frame allocator itself
+    FrameAllocator = 1,
+    /// Kernel byte heap
+    Heap,
+    /// Network rings and packet buffers
+    Network,
+    /// Frames returned to the hypervisor by the balloon driver
+    Balloon,
+    /// Debugger console ring
+    Debugger,
+}
+
+impl Owner {
+    /// All owners, in order of discriminant
+    pub const ALL: [Owner; 5] = [
+        Owner::FrameAllocator,
+        Owner::Heap,
+        Owner::Network,
+        Owner::Balloon,
+        Owner::Debugger,
+    ];
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Owner::FrameAllocator => "frame allocator",
+            Owner::Heap => "heap",
+            Owner::Network => "network",
+            Owner::Balloon => "balloon",
+            Owner::Debugger => "debugger",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Value in the owner table of free frames
+const FREE: u8 = 0;
+
+/// Contiguous range of page frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frames {
+    start: PageFrameNumber,
+    count: usize,
+}
+
+impl Frames {
+    /// Range of `count` page frames beginning at `start`
+    pub fn new(start: PageFrameNumber, count: usize) -> Self {
+        Self { start, count }
+    }
+
+    /// First page frame in the range
+    pub fn start(&self) -> PageFrameNumber {
+        self.start
+    }
+
+    /// Number of page frames in the range
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Virtual address of the first page frame
+    pub fn address(&self) -> VirtualAddress {
+        VirtualAddress::from(self.start)
+    }
+
+    /// Pointer to the first page frame
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.address().0 as *mut T
+    }
+
+    /// Machine frames backing each page frame, which are not necessarily contiguous
+    pub fn machine_frames(&self) -> impl Iterator<Item = MachineFrameNumber> {
+        (self.start.0..self.start.0 + self.count).map(|pfn| PageFrameNumber(pfn).into())
+    }
+}
+
+/// Page frame allocator
+pub struct FrameAllocator {
+    /// First page frame managed by the allocator
+    start: PageFrameNumber,
+    /// Owner of each frame, `FREE` if unallocated
+    owners: &'static mut [u8],
+    /// Index to begin searching for free frames from
+    next: usize,
+    /// Number of frames with each owner table value, including free frames
+    usage: [usize; Owner::ALL.len() + 1],
+}
+
+impl FrameAllocator {
+    /// Create a frame allocator managing `count` mapped frames beginning at `start`
+    ///
+    /// # Safety
+    ///
+    /// The frames must be mapped and unused.
+    unsafe fn new(start: PageFrameNumber, count: usize) -> Self {
+        let owners = slice::from_raw_parts_mut(VirtualAddress::from(start).0 as *mut u8, count);
+        owners.fill(FREE);
+
+        let mut usage = [0; Owner::ALL.len() + 1];
+        usage[FREE as usize] = count;
+
+        let mut celf = Self {
+            start,
+            owners,
+            next: 0,
+            usage,
+        };
+
+        // reserve the frames holding the owner table
+        let table_frames = (count + PAGE_SIZE - 1) / PAGE_SIZE;
+        celf.mark(0, table_frames, Owner::FrameAllocator as u8);
+        celf.next = table_frames;
+
+        celf
+    }
+
+    fn allocate(&mut self, owner: Owner, count: usize) -> Option<Frames> {
+        if count == 0 {
+            return None;
+        }
+
+        let len = self.owners.len();
+
+        // next-fit, wrapping around to the start once
+        let index = self
+            .find(self.next, len, count)
+            .or_else(|| self.find(0, self.next, count))?;
+
+        self.mark(index, count, owner as u8);
+        self.next = (index + count) % len;
+
+        Some(Frames {
+            start: PageFrameNumber(self.start.0 + index),
+            count,
+        })
+    }
+
+    fn allocate_aligned(&mut self, owner: Owner, count: usize, align: usize) -> Option<Frames> {
+        if count == 0 {
+            return None;
+        }
+
+        let len = self.owners.len();
+        let align = max(align, PAGE_SIZE);
+
+        // index of the first frame whose virtual address is aligned, frames are always page aligned
+        let offset = VirtualAddress::from(self.start).0 % align;
+        let first = ((align - offset) % align) / PAGE_SIZE;
+
+        // first-fit, as the next-fit position is rarely aligned
+        let index = (first..len)
+            .step_by(align / PAGE_SIZE)
+            .take_while(|index| index + count <= len)
+            .find(|&index| self.owners[index..index + count].iter().all(|&o| o == FREE))?;
+
+        self.mark(index, count, owner as u8);
+
+        Some(Frames {
+            start: PageFrameNumber(self.start.0 + index),
+            count,
+        })
+    }
+
+    fn allocate_run(&mut self, owner: Owner, limit: usize) -> Option<Frames> {
+        if limit == 0 {
+            return None;
+        }
+
+        let len = self.owners.len();
+
+        let index = (self.next..len)
+            .chain(0..self.next)
+            .find(|&index| self.owners[index] == FREE)?;
+        let count = self.owners[index..]
+            .iter()
+            .take(limit)
+            .take_while(|&&o| o == FREE)
+            .count();
+
+        self.mark(index, count, owner as u8);
+        self.next = (index + count) % len;
+
+        Some(Frames {
+            start: PageFrameNumber(self.start.0 + index),
+            count,
+        })
+    }
+
+    /// Find `count` contiguous free frames starting between `from` and `to`
+    fn find(&self, from: usize, to: usize, count: usize) -> Option<usize> {
+        let mut run = 0;
+
+        for index in from..self.owners.len() {
+            if self.owners[index] == FREE {
+                run += 1;
+
+                if run == count {
+                    return Some(index + 1 - count);
+                }
+            } else if index >= to {
+                return None;
+            } else {
+                run = 0;
+            }
+        }
+
+        None
+    }
+
+    fn free(&mut self, owner: Owner, frames: Frames) {
+        let index = frames
+            .start
+            .0
+            .checked_sub(self.start.0)
+            .filter(|index| index + frames.count <= self.owners.len())
+            .expect("Freed frames not managed by the frame allocator");
+
+        if let Some(frame) = self.owners[index..index + frames.count]
+            .iter()
+            .position(|&o| o != owner as u8)
+        {
+            panic!(
+                "Frame {} freed by {} is not owned by it",
+                frames.start.0 + frame,
+                owner
+            );
+        }
+
+        self.mark(index, frames.count, FREE);
+    }
+
+    /// Set the owner table value of a range of frames, updating usage counts
+    fn mark(&mut self, index: usize, count: usize, value: u8) {
+        for o in &mut self.owners[index..index + count] {
+            self.usage[*o as usize] -= 1;
+            self.usage[value as usize] += 1;
+            *o = value;
+        }
+    }
+
+    fn free_frames(&self) -> usize {
+        self.usage[FREE as usize]
+    }
+}
+
+/// Initialise the frame allocator with `count` mapped frames beginning at `start`
+///
+/// # Safety
+///
+/// The frames must be mapped and unused.
+pub unsafe fn init(start: PageFrameNumber, count: usize) {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+
+    if allocator.is_some() {
+        panic!("FRAME_ALLOCATOR already initialized");
+    }
+
+    *allocator = Some(FrameAllocator::new(start, count));
+}
+
+/// Allocates `count` contiguous frames to `owner`
+pub fn allocate(owner: Owner, count: usize) -> Option<Frames> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("FRAME_ALLOCATOR not initialized")
+        .allocate(owner, count)
+}
+
+/// Allocates `count` contiguous frames to `owner`, the first of which has a virtual address aligned to `align`, a power of two
+pub fn allocate_aligned(owner: Owner, count: usize, align: usize) -> Option<Frames> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("FRAME_ALLOCATOR not initialized")
+        .allocate_aligned(owner, count, align)
+}
+
+/// Allocates the next contiguous run of free frames to `owner`, of at most `limit` frames
+pub fn allocate_run(owner: Owner, limit: usize) -> Option<Frames> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("FRAME_ALLOCATOR not initialized")
+        .allocate_run(owner, limit)
+}
+
+/// Allocates `count` contiguous zeroed frames to `owner`
+pub fn allocate_zeroed(owner: Owner, count: usize) -> Option<Frames> {
+    let frames = allocate(owner, count)?;
+
+    unsafe { ptr::write_bytes(frames.as_mut_ptr::<u8>(), 0, count * PAGE_SIZE) };
+
+    Some(frames)
+}
+
+/// Frees frames previously allocated to `owner`, panicking if any are not owned by it
+pub fn free(owner: Owner, frames: Frames) {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("FRAME_ALLOCATOR not initialized")
+        .free(owner, frames)
+}
+
+/// Gets the number of frames that are not allocated
+pub fn free_frames() -> usize {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(FrameAllocator::free_frames)
+        .unwrap_or(0)
+}
+
+/// Gets the number of frames allocated to each owner
+pub fn usage() -> [(Owner, usize); Owner::ALL.len()] {
+    let allocator = FRAME_ALLOCATOR.lock();
+    let allocator = allocator.as_ref().expect("FRAME_ALLOCATOR not initialized");
+
+    let mut usage = [(Owner::FrameAllocator, 0); Owner::ALL.len()];
+    for (entry, owner) in usage.iter_mut().zip(Owner::ALL.iter()) {
+        *entry = (*owner, allocator.usage[*owner as usize]);
+    }
+
+    usage
+}
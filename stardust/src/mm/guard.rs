@@ -0,0 +1,63 @@
+//! Stack guard pages
+//!
+//! A page below each stack is unmapped so that an overflow faults instead of silently overwriting
+//! whatever lies below it. Xen writes the exception frame to the faulting stack, so a fault with the
+//! stack pointer already in the guard page crashes the domain before any handler runs. The lowest
+//! mapped page of each stack is therefore treated as a red zone: the event callback and trap handlers
+//! report an overflow if the interrupted stack pointer is in it, from a separate stack. An overflow that
+//! skips the red zone, such as a frame larger than a page, is not detected, see the README.
+
+use {
+    alloc::vec::Vec,
+    log::debug,
+    spin::Mutex,
+    xen::{
+        memory::{virtual_memory, VirtualAddress},
+        platform::{consts::PAGE_SIZE, util::irq_stack_guard},
+        sections::stack_guard,
+    },
+};
+
+/// Bytes at the bottom of each stack, directly above its guard page, in which the stack is considered overflowed
+pub const RED_ZONE_SIZE: usize = PAGE_SIZE;
+
+/// Name and address of each guard page
+static GUARDS: Mutex<Vec<(&'static str, VirtualAddress)>> = Mutex::new(Vec::new());
+
+/// Unmap the guard pages of the boot and event stacks
+pub fn init() {
+    register("boot", VirtualAddress(stack_guard()));
+    register("event", VirtualAddress(irq_stack_guard()));
+}
+
+/// Unmap the page at `guard`, directly below the stack `name`
+pub fn register(name: &'static str, guard: VirtualAddress) {
+    debug!("stack guard page for {} at {:#x}", name, guard.0);
+
+    // SAFETY: the guard page is reserved below the stack and never used
+    unsafe { virtual_memory::unmap(guard, 1) }.expect("Failed to unmap stack guard page");
+
+    GUARDS.lock().push((name, guard));
+}
+
+/// Gets the name of the stack whose red zone or guard page contains the stack pointer `rsp`
+///
+/// Called from trap handlers so returns `None` if the guard pages are locked.
+pub fn overflowed(rsp: VirtualAddress) -> Option<&'static str> {
+    GUARDS
+        .try_lock()?
+        .iter()
+        .find(|(_, guard)| (guard.0..guard.0 + PAGE_SIZE + RED_ZONE_SIZE).contains(&rsp.0))
+        .map(|(name, _)| *name)
+}
+
+/// Gets the name of the stack whose guard page contains `address`
+///
+/// Called from the page fault handler so returns `None` if the guard pages are locked.
+pub fn find(address: VirtualAddress) -> Option<&'static str> {
+    GUARDS
+        .try_lock()?
+        .iter()
+        .find(|(_, guard)| (guard.0..guard.0 + PAGE_SIZE).contains(&address.0))
+        .map(|(name, _)| *name)
+}
@@ -1,20 +1,28 @@
 //! Kernel memory management
 
 use {
-    core::{cmp::min, convert::TryInto},
+    core::{
+        cmp::{max, min},
+        convert::TryInto,
+    },
     log::{debug, info},
     xen::{
         memory::{
-            get_current_pages, get_max_pages, page_table, pfn_up, PageEntry, PageFrameNumber,
-            PhysicalAddress, VirtualAddress,
+            get_current_pages, get_max_pages, page_table, pfn_up, virtual_memory, PageEntry,
+            PageFrameNumber, PhysicalAddress, Protection, VirtualAddress,
         },
         platform::consts::{MAX_MEM_SIZE, PAGE_SIZE},
-        sections::end,
+        sections::{end, erodata, etext, text_start},
         xen_sys::start_info_t,
+        SHARED_INFO,
     },
 };
 
 pub mod allocator;
+pub mod balloon;
+pub mod frame;
+pub mod guard;
+pub mod stats;
 
 /// Initialise kernel memory management
 pub fn init(start_info: &start_info_t) {
@@ -59,5 +67,44 @@ pub fn init(start_info: &start_info_t) {
 
     let (start_address, size) = unsafe { page_table::build(pt_base, start_pfn, max_pfn) };
 
-    unsafe { allocator::init(start_address, size) };
+    unsafe { frame::init(PageFrameNumber::from(start_address), size / PAGE_SIZE) };
+
+    // the heap starts small and grows from the frame allocator on demand
+    let heap = frame::allocate(frame::Owner::Heap, allocator::INITIAL_FRAMES)
+        .expect("Failed to allocate heap frames");
+
+    unsafe { allocator::init(heap.address(), heap.count() * PAGE_SIZE) };
+
+    protect_kernel(
+        pt_base as usize,
+        start_address,
+        VirtualAddress(start_address.0 + size),
+    );
+
+    guard::init();
+}
+
+/// Remap the kernel image and memory so that no page is both writable and executable
+///
+/// Page table frames must remain read-only so are skipped: those supplied by Xen begin at `pt_base`,
+/// and those created by `page_table::build` precede `start`, the first usable page.
+fn protect_kernel(pt_base: usize, start: VirtualAddress, end: VirtualAddress) {
+    let pages = |from: usize, to: usize| (to - from) / PAGE_SIZE;
+
+    // the first page of text is replaced by the shared info page, which remains writable
+    let text = max(text_start(), unsafe { SHARED_INFO } as usize + PAGE_SIZE);
+
+    let ranges = [
+        (text, etext(), Protection::EXECUTE),
+        (etext(), erodata(), Protection::empty()),
+        (erodata(), pt_base, Protection::WRITE),
+        (start.0, end.0, Protection::WRITE),
+    ];
+
+    for (from, to, protection) in ranges.iter() {
+        debug!("protecting {:#x} - {:#x} as {:?}", from, to, protection);
+
+        unsafe { virtual_memory::protect(VirtualAddress(*from), pages(*from, *to), *protection) }
+            .expect("Failed to protect kernel memory");
+    }
 }
@@ -0,0 +1,320 @@
+//! Heap allocation statistics
+//!
+//! Counts allocations, frees, bytes in use and peak usage of the global allocator. With the `alloc-debug`
+//! feature every allocation is prefixed with a header recording its size and the tag that was current
+//! when it was made, so usage can be broken down per tag and outstanding allocations reported.
+//!
+//! The executor sets the tag to the name of each task as it is polled. Each vCPU has its own tag, as
+//! each polls its own tasks.
+
+use {
+    alloc::alloc::Layout,
+    core::{
+        cell::Cell,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    log::info,
+    xen::percpu,
+};
+
+/// Tag of allocations made outside of any task
+pub const KERNEL_TAG: &str = "kernel";
+
+percpu! {
+    /// Tag attributed to new allocations
+    static TAG: Cell<&'static str> = Cell::new(KERNEL_TAG);
+}
+
+/// Statistics of all allocations
+static TOTAL: Counters = Counters::new();
+
+/// Allocation statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Number of allocations made
+    pub allocations: usize,
+    /// Number of allocations freed
+    pub frees: usize,
+    /// Bytes currently allocated, as requested by callers
+    pub bytes: usize,
+    /// Largest value of `bytes` seen
+    pub peak: usize,
+}
+
+impl Stats {
+    /// Number of allocations not yet freed
+    pub fn outstanding(&self) -> usize {
+        self.allocations - self.frees
+    }
+}
+
+/// Lock-free allocation counters
+struct Counters {
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    bytes: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+        }
+    }
+
+    fn alloc(&self, size: usize) {
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        let bytes = self.bytes.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak.fetch_max(bytes, Ordering::Relaxed);
+    }
+
+    fn free(&self, size: usize) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Stats {
+        Stats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Sets the tag attributed to new allocations, returning the previous tag
+pub fn set_tag(tag: &'static str) -> &'static str {
+    TAG.get().replace(tag)
+}
+
+/// Gets statistics of all allocations
+pub fn stats() -> Stats {
+    TOTAL.get()
+}
+
+/// Logs allocation statistics, called on panic so must not allocate
+pub fn dump() {
+    let total = stats();
+    info!(
+        "heap: {} allocations, {} frees, {} bytes in use, {} bytes peak",
+        total.allocations, total.frees, total.bytes, total.peak
+    );
+
+    #[cfg(feature = "alloc-debug")]
+    debug::dump();
+}
+
+/// Layout to request from the heap to satisfy `layout`
+pub(super) fn layout(layout: Layout) -> Layout {
+    #[cfg(feature = "alloc-debug")]
+    let layout = debug::layout(layout);
+
+    layout
+}
+
+/// Record an allocation of `layout` at `ptr` returned by the heap, returning the pointer handed to the caller
+///
+/// # Safety
+///
+/// `ptr` must have been allocated with `self::layout(layout)`.
+pub(super) unsafe fn on_alloc(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    TOTAL.alloc(layout.size());
+
+    #[cfg(feature = "alloc-debug")]
+    let ptr = debug::track(ptr, layout);
+
+    ptr
+}
+
+/// Record freeing an allocation of `layout` at `ptr`, returning the pointer to free from the heap
+///
+/// # Safety
+///
+/// `ptr` must have been returned by `on_alloc` with the same layout.
+pub(super) unsafe fn on_dealloc(ptr: *mut u8, layout: Layout) -> *mut u8 {
+    TOTAL.free(layout.size());
+
+    #[cfg(feature = "alloc-debug")]
+    let ptr = debug::untrack(ptr, layout);
+
+    ptr
+}
+
+/// Gets statistics of allocations made under each tag
+#[cfg(feature = "alloc-debug")]
+pub fn tags() -> alloc::vec::Vec<(&'static str, Stats)> {
+    // copied out as collecting allocates, which updates the tags
+    let tags = *debug::TAGS.lock();
+    tags.iter().flatten().copied().collect()
+}
+
+/// Logs every allocation that has not been freed, intended to be called at shutdown
+#[cfg(feature = "alloc-debug")]
+pub fn report_outstanding() {
+    debug::report_outstanding()
+}
+
+#[cfg(feature = "alloc-debug")]
+mod debug {
+    use {
+        super::{Stats, TAG},
+        alloc::alloc::Layout,
+        core::{cmp::max, mem::size_of, ptr},
+        log::{info, warn},
+        spin::Mutex,
+    };
+
+    /// Maximum number of distinct tags tracked, further tags are counted under the last entry
+    const MAX_TAGS: usize = 32;
+
+    /// Maximum number of outstanding allocations logged individually
+    const MAX_REPORTED: usize = 64;
+
+    /// Statistics of each tag, in order of first allocation
+    pub(super) static TAGS: Mutex<[Option<(&'static str, Stats)>; MAX_TAGS]> =
+        Mutex::new([None; MAX_TAGS]);
+
+    /// Most recent outstanding allocation, forming a list through each header
+    static HEAD: Mutex<Header> = Mutex::new(Header::empty());
+
+    /// Header preceding each allocation
+    struct Header {
+        prev: *mut Header,
+        next: *mut Header,
+        size: usize,
+        tag: &'static str,
+    }
+
+    // SAFETY: headers are only accessed with `HEAD` locked
+    unsafe impl Send for Header {}
+
+    impl Header {
+        const fn empty() -> Self {
+            Self {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                size: 0,
+                tag: "",
+            }
+        }
+    }
+
+    /// Offset of the caller's pointer from the start of the allocation, keeping its alignment
+    fn offset(layout: Layout) -> usize {
+        max(layout.align(), size_of::<Header>().next_power_of_two())
+    }
+
+    pub(super) fn layout(layout: Layout) -> Layout {
+        Layout::from_size_align(layout.size() + offset(layout), layout.align())
+            .expect("Failed to create tracked layout")
+    }
+
+    pub(super) unsafe fn track(ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let ptr = ptr.add(offset(layout));
+        let header = (ptr as *mut Header).sub(1);
+
+        let tag = TAG.get().get();
+        update(tag, |stats| {
+            stats.allocations += 1;
+            stats.bytes += layout.size();
+            stats.peak = max(stats.peak, stats.bytes);
+        });
+
+        let mut head = HEAD.lock();
+        header.write(Header {
+            prev: &mut *head as *mut Header,
+            next: head.next,
+            size: layout.size(),
+            tag,
+        });
+        if !head.next.is_null() {
+            (*head.next).prev = header;
+        }
+        head.next = header;
+
+        ptr
+    }
+
+    pub(super) unsafe fn untrack(ptr: *mut u8, layout: Layout) -> *mut u8 {
+        let header = (ptr as *mut Header).sub(1);
+
+        {
+            let _head = HEAD.lock();
+            (*(*header).prev).next = (*header).next;
+            if !(*header).next.is_null() {
+                (*(*header).next).prev = (*header).prev;
+            }
+        }
+
+        update((*header).tag, |stats| {
+            stats.frees += 1;
+            stats.bytes -= layout.size();
+        });
+
+        ptr.sub(offset(layout))
+    }
+
+    /// Apply `f` to the statistics of `tag`
+    fn update<F: FnOnce(&mut Stats)>(tag: &'static str, f: F) {
+        let mut tags = TAGS.lock();
+
+        let index = tags
+            .iter()
+            .position(|entry| entry.map(|(t, _)| t == tag).unwrap_or(true))
+            .unwrap_or(MAX_TAGS - 1);
+
+        let (_, stats) = tags[index].get_or_insert((tag, Stats::default()));
+        f(stats);
+    }
+
+    pub(super) fn dump() {
+        // the panic may have occurred with the tags locked, and logging may allocate
+        if let Some(tags) = TAGS.try_lock().map(|tags| *tags) {
+            for (tag, stats) in tags.iter().flatten() {
+                info!(
+                    "heap {}: {} allocations, {} frees, {} bytes in use, {} bytes peak",
+                    tag, stats.allocations, stats.frees, stats.bytes, stats.peak
+                );
+            }
+        }
+    }
+
+    pub(super) fn report_outstanding() {
+        let mut reported = [(ptr::null_mut(), 0, ""); MAX_REPORTED];
+        let mut count = 0;
+        let mut bytes = 0;
+
+        // copy out before logging, which may allocate
+        {
+            let head = HEAD.lock();
+            let mut header = head.next;
+
+            while !header.is_null() {
+                // SAFETY: headers in the list are valid until untracked, which requires `HEAD`
+                let h = unsafe { &*header };
+
+                if count < MAX_REPORTED {
+                    reported[count] = (unsafe { header.add(1) } as *mut u8, h.size, h.tag);
+                }
+
+                count += 1;
+                bytes += h.size;
+                header = h.next;
+            }
+        }
+
+        for (ptr, size, tag) in reported.iter().take(count) {
+            warn!(
+                "outstanding allocation of {} bytes at {:p} ({})",
+                size, ptr, tag
+            );
+        }
+
+        info!("{} outstanding allocations, {} bytes", count, bytes);
+    }
+}
@@ -1,26 +1,56 @@
 //! Network front-end driver
 
 use {
+    crate::{control, mm::allocator},
     alloc::{collections::BTreeMap, vec, vec::Vec},
-    core::{fmt::Write, str},
+    core::{
+        fmt::Write,
+        str,
+        sync::atomic::{AtomicBool, Ordering},
+    },
     log::{info, warn},
     phy::Device,
     smoltcp::{
         iface::{InterfaceBuilder, NeighborCache, Routes},
-        socket::{TcpSocket, TcpSocketBuffer},
+        socket::{TcpSocket, TcpSocketBuffer, TcpState},
         time::Instant,
-        wire::{IpCidr, Ipv4Address},
+        wire::{EthernetAddress, IpCidr, IpEndpoint, Ipv4Address},
     },
+    spin::Mutex,
     xen::time::get_system_time,
 };
 
 mod phy;
 mod ring;
+mod syslog;
+
+/// Set after resuming in a new domain, the server task reconnects the device to the new backend
+static RECONNECT: AtomicBool = AtomicBool::new(false);
+
+/// State of the network interface and its sockets, updated by the server task
+pub static STATUS: Mutex<Option<Status>> = Mutex::new(None);
+
+/// Snapshot of network interface and socket state
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// Hardware address of the interface
+    pub mac: EthernetAddress,
+    /// IP addresses assigned to the interface
+    pub ip_addrs: Vec<IpCidr>,
+    /// State of the TCP server socket
+    pub tcp: TcpState,
+    /// Address of the syslog collector, if configured
+    pub syslog: Option<IpEndpoint>,
+}
 
 pub async fn server() {
     let phy = Device::new().await;
     let mac = phy.mac();
 
+    control::register(phy::close);
+    allocator::register_oom_handler(phy::shed);
+    control::register_resume(|| RECONNECT.store(true, Ordering::Relaxed));
+
     let neighbor_cache = NeighborCache::new(BTreeMap::new());
 
     let ip_addrs = [IpCidr::new(Ipv4Address::new(192, 168, 1, 2).into(), 0)];
@@ -43,9 +73,22 @@ pub async fn server() {
 
     let tcp_handle = iface.add_socket(socket);
 
+    let mut syslog = syslog::Syslog::new(&mut iface);
+
+    *STATUS.lock() = Some(Status {
+        mac,
+        ip_addrs: iface.ip_addrs().to_vec(),
+        tcp: TcpState::Closed,
+        syslog: syslog.as_ref().map(|syslog| syslog.collector()),
+    });
+
     info!("starting TCP server");
 
     loop {
+        if RECONNECT.swap(false, Ordering::Relaxed) {
+            iface.device_mut().reconnect().await;
+        }
+
         match iface.poll(Instant::from_micros((get_system_time() >> 10) as i64)) {
             Ok(_) => {}
             Err(e) => {
@@ -53,11 +96,19 @@ pub async fn server() {
             }
         }
 
+        if let Some(syslog) = &mut syslog {
+            syslog.poll(&mut iface);
+        }
+
         let socket = iface.get_socket::<TcpSocket>(tcp_handle);
         if !socket.is_open() {
             socket.listen(80).unwrap();
         }
 
+        if let Some(status) = STATUS.lock().as_mut() {
+            status.tcp = socket.state();
+        }
+
         if socket.can_recv() {
             let mut recv_buf = vec![0; 2048];
             let len = socket.recv_slice(&mut recv_buf).unwrap();
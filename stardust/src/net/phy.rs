@@ -1,6 +1,10 @@
 use {
-    super::ring::{RawRing, Ring, PAGE_LAYOUT},
-    alloc::{alloc::alloc, format, vec, vec::Vec},
+    super::ring::{RawRing, Ring},
+    crate::{
+        control::Request,
+        mm::frame::{self, Frames, Owner},
+    },
+    alloc::{format, vec, vec::Vec},
     core::{
         ptr::{self, copy_nonoverlapping},
         sync::atomic::{fence, Ordering},
@@ -11,10 +15,12 @@ use {
         time::Instant,
         wire::EthernetAddress,
     },
+    spin::Mutex,
     xen::{
         events::{bind_event_channel, event_channel_op},
         grant_table,
-        memory::{MachineFrameNumber, VirtualAddress},
+        memory::{MachineFrameNumber, PageFrameNumber, VirtualAddress},
+        time::get_monotonic_time,
         xen_sys::{
             self, domid_t, evtchn_alloc_unbound_t, evtchn_port_t, evtchn_send, grant_ref_t,
             netif_rx_request, netif_rx_sring, netif_tx_request, netif_tx_sring,
@@ -27,6 +33,15 @@ use {
 
 const RING_SIZE: usize = 256;
 
+/// Watch token for the state of the backend
+const BACKEND_STATE_TOKEN: &str = "vif-backend-state\0";
+
+/// Nanoseconds to wait for the backend to follow a state change when closing
+const BACKEND_TIMEOUT: u64 = 5_000_000_000;
+
+/// Transmit buffer pages not currently in use, freed when memory runs out
+static TX_PAGES: Mutex<Vec<Frames>> = Mutex::new(Vec::new());
+
 struct Freelist([usize; RING_SIZE + 1]);
 
 impl Freelist {
@@ -90,7 +105,9 @@ impl Device {
         }
 
         for i in 0..RING_SIZE {
-            rx_buffers[i].page = unsafe { alloc(PAGE_LAYOUT) };
+            rx_buffers[i].page = frame::allocate(Owner::Network, 1)
+                .expect("Failed to allocate rx buffer frame")
+                .as_mut_ptr();
         }
 
         // get domain ID of backend
@@ -135,6 +152,35 @@ impl Device {
         celf
     }
 
+    /// Reconnects to the backend after resuming in a new domain
+    ///
+    /// All grants and event channels were lost, so fresh rings are shared with the backend and any
+    /// packets in flight are dropped.
+    pub async fn reconnect(&mut self) {
+        self.backend_domain = get_backend_domain();
+        self.event_channel_port = alloc_event_channel(self.backend_domain);
+
+        self.tx = Ring::<netif_tx_sring>::new();
+        self.rx = Ring::<netif_rx_sring>::new();
+
+        self.tx_freelist = Freelist::new();
+        for i in 0..RING_SIZE {
+            self.tx_freelist.add(i);
+        }
+
+        let txs = MachineFrameNumber::from(VirtualAddress(self.tx.sring as *mut _ as usize));
+        let rxs = MachineFrameNumber::from(VirtualAddress(self.rx.sring as *mut _ as usize));
+
+        self.tx_ring_ref = grant_table::grant_access(self.backend_domain, txs, false);
+        self.rx_ring_ref = grant_table::grant_access(self.backend_domain, rxs, false);
+
+        self.init_rx_buffers();
+
+        self.connect().await;
+
+        log::info!("reconnected to backend domain {}", self.backend_domain);
+    }
+
     fn notify(&self) {
         let mut event = evtchn_send {
             port: self.event_channel_port,
@@ -263,12 +309,14 @@ impl Device {
         let rsp = xenbus::request(MessageKind::TransactionEnd, &[b"T\0"], txn_id).await;
         log::trace!("{:?}", rsp);
 
-        log::trace!(
-            "backend: {:?}",
-            xenbus::request(MessageKind::Read, &[b"device/vif/0/backend\0"], 0)
-                .await
-                .1,
-        );
+        let backend = xenbus::request(MessageKind::Read, &[b"device/vif/0/backend\0"], 0)
+            .await
+            .1;
+        log::trace!("backend: {:?}", backend);
+
+        // watched so that `close` can wait for the backend to follow each state change
+        xenbus::watch(&format!("{}/state\0", backend), BACKEND_STATE_TOKEN).await;
+
         log::trace!(
             "mac: {:?}",
             xenbus::request(MessageKind::Read, &[b"device/vif/0/mac\0"], 0)
@@ -388,7 +436,11 @@ impl Device {
         log::trace!("tx id {}", id);
 
         if self.tx_buffers[id].page.is_null() {
-            self.tx_buffers[id].page = unsafe { alloc(PAGE_LAYOUT) };
+            let page = TX_PAGES.lock().pop();
+            self.tx_buffers[id].page = page
+                .or_else(|| frame::allocate(Owner::Network, 1))
+                .expect("Failed to allocate tx buffer frame")
+                .as_mut_ptr();
         }
 
         log::trace!("tx buffers {:?}", self.tx_buffers[id]);
@@ -464,6 +516,11 @@ impl Device {
                 grant_table::grant_end(buf.grant_ref);
                 buf.grant_ref = 0;
 
+                // cache the page so it can be freed under memory pressure
+                let pfn = PageFrameNumber::from(VirtualAddress(buf.page as usize));
+                TX_PAGES.lock().push(Frames::new(pfn, 1));
+                self.tx_buffers[id].page = ptr::null_mut();
+
                 self.tx_freelist.add(id);
 
                 cons += 1;
@@ -476,9 +533,65 @@ impl Device {
     }
 }
 
+/// Frees cached transmit buffer pages, registered as an out-of-memory handler
+pub fn shed() {
+    // the cache may be locked by the allocation that ran out of memory
+    if let Some(mut pages) = TX_PAGES.try_lock() {
+        for page in pages.drain(..) {
+            frame::free(Owner::Network, page);
+        }
+    }
+}
+
+/// Closes the connection to the backend, waiting for it to follow, called before the domain shuts down
+///
+/// The connection is left open when suspending as the backend is torn down by the toolstack, the device reconnects after resuming.
+pub fn close(request: Request) {
+    if request == Request::Suspend {
+        return;
+    }
+
+    // the backend must follow each state before the next is written, or it may not release the rings
+    for state in [xenbus::State::Closing, xenbus::State::Closed] {
+        xenstore::write(
+            "device/vif/0/state\0",
+            format!("{}", xen_sys::xenbus_state::from(state)),
+        )
+        .expect("Failed to write frontend state");
+
+        if !wait_for_backend(state) {
+            log::warn!("timed out waiting for backend to reach {:?}", state);
+        }
+    }
+}
+
+/// Waits for the backend to reach `state`, or to be closed or removed, returning `false` if it does not within `BACKEND_TIMEOUT`
+fn wait_for_backend(state: xenbus::State) -> bool {
+    let backend = xenstore::read("device/vif/0/backend\0").expect("Failed to read backend path");
+    let path = format!("{}/state\0", backend);
+    let deadline = get_monotonic_time() + BACKEND_TIMEOUT;
+
+    loop {
+        // the node is removed along with the backend
+        let current = xenstore::read(&path)
+            .expect("Failed to read backend state")
+            .parse::<u32>()
+            .map(xenbus::State::from)
+            .unwrap_or(xenbus::State::Unknown);
+
+        if current == state || matches!(current, xenbus::State::Closed | xenbus::State::Unknown) {
+            return true;
+        }
+
+        if xenbus::wait_watch_event(BACKEND_STATE_TOKEN, deadline).is_none() {
+            return false;
+        }
+    }
+}
+
 fn get_mac() -> EthernetAddress {
     let mut buf = [0; 6];
-    let s = xenstore::read("device/vif/0/mac\0");
+    let s = xenstore::read("device/vif/0/mac\0").expect("Failed to read MAC address");
 
     log::trace!("mac: {}", s);
 
@@ -493,6 +606,7 @@ fn get_mac() -> EthernetAddress {
 
 fn get_backend_domain() -> domid_t {
     xenstore::read("device/vif/0/backend-id\0")
+        .expect("Failed to read backend-id")
         .parse::<domid_t>()
         .expect("failed to parse backend-id")
 }
@@ -1,5 +1,5 @@
 use {
-    alloc::alloc::{alloc_zeroed, dealloc, Layout},
+    crate::mm::frame::{self, Frames, Owner},
     core::{
         fmt::Debug,
         mem::size_of,
@@ -12,18 +12,17 @@ use {
     },
 };
 
-pub const PAGE_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
-
 pub struct Ring<S: 'static + RawRing> {
     pub req_prod_pvt: u32,
     pub rsp_cons: u32,
     pub nr_ents: u32,
     pub sring: &'static mut S,
+    frame: Frames,
 }
 
 impl<S: RawRing> Drop for Ring<S> {
     fn drop(&mut self) {
-        unsafe { dealloc(self.sring as *mut _ as *mut u8, PAGE_LAYOUT) }
+        frame::free(Owner::Network, self.frame)
     }
 }
 
@@ -43,11 +42,15 @@ impl<S: RawRing> Debug for Ring<S> {
 
 impl<S: RawRing> Ring<S> {
     pub fn new() -> Self {
+        let frame =
+            frame::allocate_zeroed(Owner::Network, 1).expect("Failed to allocate ring frame");
+
         Self {
             req_prod_pvt: 0,
             rsp_cons: 0,
             nr_ents: S::size() as u32,
-            sring: unsafe { &mut *S::new() },
+            sring: unsafe { &mut *S::new(frame.as_mut_ptr()) },
+            frame,
         }
     }
 
@@ -98,7 +101,8 @@ impl<S: RawRing> Ring<S> {
 pub trait RawRing {
     type Element;
 
-    fn new() -> *mut Self;
+    /// Initialise a shared ring in the supplied zeroed page
+    fn new(page: *mut Self) -> *mut Self;
 
     fn size() -> usize;
 
@@ -120,8 +124,7 @@ pub trait RawRing {
 impl RawRing for netif_tx_sring {
     type Element = netif_tx_sring_entry;
 
-    fn new() -> *mut Self {
-        let ptr = unsafe { alloc_zeroed(PAGE_LAYOUT) as *mut netif_tx_sring };
+    fn new(ptr: *mut Self) -> *mut Self {
         unsafe {
             (*ptr).req_event = 1;
             (*ptr).rsp_event = 1;
@@ -180,8 +183,7 @@ impl RawRing for netif_tx_sring {
 impl RawRing for netif_rx_sring {
     type Element = netif_rx_sring_entry;
 
-    fn new() -> *mut Self {
-        let ptr = unsafe { alloc_zeroed(PAGE_LAYOUT) as *mut netif_rx_sring };
+    fn new(ptr: *mut Self) -> *mut Self {
         unsafe {
             (*ptr).req_event = 1;
             (*ptr).rsp_event = 1;
@@ -0,0 +1,190 @@
+//! Remote syslog log shipping
+//!
+//! Records from the log buffer are sent to a collector as RFC 5424 syslog messages over UDP. The
+//! collector is configured with the `syslog=<address>:<port>` command line option, and the minimum
+//! level of shipped records with `syslog.level=<level>` (defaults to `info`). Records logged before
+//! the network is up are kept in the log buffer and sent once the stack is first polled, the
+//! console continues to receive every record.
+
+use {
+    super::phy::Device,
+    crate::{config, logger::buffer::BUFFER},
+    alloc::{string::String, vec},
+    core::{fmt::Write, str::FromStr},
+    log::{info, warn, Level},
+    smoltcp::{
+        iface::{Interface, SocketHandle},
+        socket::{UdpPacketMetadata, UdpSocket, UdpSocketBuffer},
+        wire::IpEndpoint,
+    },
+    xen::{
+        time::{get_monotonic_time, get_system_time},
+        xenstore,
+    },
+};
+
+/// Local port syslog messages are sent from
+const LOCAL_PORT: u16 = 514;
+
+/// Number of messages that can be queued in the socket before being transmitted
+const QUEUE_LEN: usize = 16;
+
+/// Facility of all messages, stardust is the kernel
+const FACILITY_KERNEL: u8 = 0;
+
+/// Log sink sending records to a remote syslog collector
+pub struct Syslog {
+    handle: SocketHandle,
+    collector: IpEndpoint,
+    level: Level,
+    hostname: String,
+    /// Sequence number of the next record in the log buffer to be sent
+    next_sequence: u64,
+}
+
+impl Syslog {
+    /// Creates a syslog sink on the supplied interface, returning `None` if no collector is configured or its socket cannot be bound
+    pub fn new(iface: &mut Interface<'_, Device>) -> Option<Self> {
+        let collector = match IpEndpoint::from_str(config::get("syslog")?) {
+            Ok(collector) => collector,
+            Err(()) => {
+                warn!("invalid syslog collector address, expected <address>:<port>");
+                return None;
+            }
+        };
+
+        let level = config::get("syslog.level")
+            .and_then(|level| Level::from_str(level).ok())
+            .unwrap_or(Level::Info);
+
+        let mut socket = UdpSocket::new(
+            UdpSocketBuffer::new(vec![UdpPacketMetadata::EMPTY; 1], vec![0; 0]),
+            UdpSocketBuffer::new(
+                vec![UdpPacketMetadata::EMPTY; QUEUE_LEN],
+                vec![0; QUEUE_LEN * 1024],
+            ),
+        );
+        if let Err(e) = socket.bind(LOCAL_PORT) {
+            warn!("failed to bind syslog socket, syslog disabled: {}", e);
+            return None;
+        }
+
+        let hostname = xenstore::read("name\0").expect("Failed to read domain name");
+
+        info!(
+            "shipping logs at level {} and above to {}",
+            level, collector
+        );
+
+        Some(Self {
+            handle: iface.add_socket(socket),
+            collector,
+            level,
+            hostname,
+            next_sequence: 0,
+        })
+    }
+
+    /// Address of the collector records are sent to
+    pub fn collector(&self) -> IpEndpoint {
+        self.collector
+    }
+
+    /// Queue records logged since the last poll for transmission
+    pub fn poll(&mut self, iface: &mut Interface<'_, Device>) {
+        let socket = iface.get_socket::<UdpSocket>(self.handle);
+
+        // wall clock time at boot, log buffer timestamps are relative to boot
+        let boot_time = get_system_time() - get_monotonic_time();
+
+        while socket.can_send() {
+            // copy the record out so the buffer is not locked while sending as smoltcp may log
+            let entry = match BUFFER.lock().read(self.next_sequence) {
+                Some(entry) => entry,
+                None => break,
+            };
+
+            if entry.level > self.level {
+                self.next_sequence = entry.sequence + 1;
+                continue;
+            }
+
+            let mut message = String::new();
+            write!(message, "<{}>1 ", priority(entry.level)).unwrap();
+            write_timestamp(&mut message, boot_time + entry.timestamp);
+            write!(
+                message,
+                " {} stardust - {} - {}",
+                self.hostname,
+                msg_id(entry.target()),
+                entry.message()
+            )
+            .unwrap();
+
+            if socket
+                .send_slice(message.as_bytes(), self.collector)
+                .is_err()
+            {
+                break;
+            }
+
+            self.next_sequence = entry.sequence + 1;
+        }
+    }
+}
+
+/// Syslog priority value of a record
+fn priority(level: Level) -> u8 {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+
+    FACILITY_KERNEL * 8 + severity
+}
+
+/// RFC 5424 MSGID is limited to 32 printable characters
+fn msg_id(target: &str) -> &str {
+    match target.char_indices().nth(32) {
+        Some((end, _)) => &target[..end],
+        None => target,
+    }
+}
+
+/// Write a nanosecond UNIX timestamp in the RFC 3339 format required by RFC 5424
+fn write_timestamp(out: &mut String, nanoseconds: u64) {
+    let seconds = nanoseconds / 1_000_000_000;
+    let (year, month, day) = civil_from_days(seconds / 86400);
+
+    write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        year,
+        month,
+        day,
+        (seconds % 86400) / 3600,
+        (seconds % 3600) / 60,
+        seconds % 60,
+        (nanoseconds % 1_000_000_000) / 1000
+    )
+    .unwrap();
+}
+
+/// Convert a number of days since 1970-01-01 to a (year, month, day) date
+///
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
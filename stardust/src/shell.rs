@@ -0,0 +1,284 @@
+//! Interactive debug shell on the Xen console
+//!
+//! Enabled with the `shell` feature.
+
+use {
+    crate::{
+        control::{self, Request},
+        executor,
+        mm::{allocator::ALLOCATOR, balloon, frame, stats},
+        net,
+    },
+    alloc::{format, vec::Vec},
+    core::str::FromStr,
+    log::LevelFilter,
+    xen::{console::Reader, grant_table, print, println, xenstore},
+};
+
+/// Shell command
+struct Command {
+    /// Name used to invoke the command
+    name: &'static str,
+    /// Description of arguments
+    usage: &'static str,
+    /// Description of the command
+    help: &'static str,
+    /// Command implementation, supplied with the arguments following the name
+    run: fn(&[&str]),
+}
+
+const COMMANDS: [Command; 12] = [
+    Command {
+        name: "help",
+        usage: "",
+        help: "list available commands",
+        run: help,
+    },
+    Command {
+        name: "tasks",
+        usage: "",
+        help: "list tasks on the executor",
+        run: tasks,
+    },
+    Command {
+        name: "heap",
+        usage: "",
+        help: "show allocator statistics",
+        run: heap,
+    },
+    Command {
+        name: "frames",
+        usage: "",
+        help: "show page frames allocated to each subsystem",
+        run: frames,
+    },
+    Command {
+        name: "grants",
+        usage: "",
+        help: "list grant references in use",
+        run: grants,
+    },
+    Command {
+        name: "xs-read",
+        usage: "<key>",
+        help: "read a XenStore key",
+        run: xs_read,
+    },
+    Command {
+        name: "xs-write",
+        usage: "<key> <value>",
+        help: "write a XenStore key",
+        run: xs_write,
+    },
+    Command {
+        name: "xs-ls",
+        usage: "<key>",
+        help: "list the children of a XenStore key",
+        run: xs_ls,
+    },
+    Command {
+        name: "net",
+        usage: "",
+        help: "show network interface and socket state",
+        run: network,
+    },
+    Command {
+        name: "log",
+        usage: "<off|error|warn|info|debug|trace>",
+        help: "set the maximum log level",
+        run: log_level,
+    },
+    Command {
+        name: "reboot",
+        usage: "",
+        help: "reboot the domain",
+        run: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "",
+        help: "power off the domain",
+        run: poweroff,
+    },
+];
+
+/// Shell task, reads and executes commands from the console until the domain shuts down
+pub async fn task() {
+    println!("stardust debug shell, enter `help` for a list of commands");
+
+    loop {
+        print!("> ");
+
+        let line = Reader::read_line().await;
+        let mut words = line.split_whitespace();
+
+        let name = match words.next() {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let args = words.collect::<Vec<_>>();
+
+        match COMMANDS.iter().find(|command| command.name == name) {
+            Some(command) => (command.run)(&args),
+            None => println!(
+                "unknown command {:?}, enter `help` for a list of commands",
+                name
+            ),
+        }
+    }
+}
+
+fn help(_: &[&str]) {
+    for command in COMMANDS.iter() {
+        println!(
+            "{:<30} {}",
+            format!("{} {}", command.name, command.usage),
+            command.help
+        );
+    }
+}
+
+fn tasks(_: &[&str]) {
+    println!(
+        "{:>4} {:<16} {:>10} {:>12}",
+        "ID", "NAME", "POLLS", "MAX POLL US"
+    );
+    for task in executor::tasks() {
+        println!(
+            "{:>4} {:<16} {:>10} {:>12}",
+            task.id,
+            task.name,
+            task.polls,
+            task.longest_poll / 1_000
+        );
+    }
+}
+
+fn heap(_: &[&str]) {
+    let (total, actual, user) = {
+        let heap = ALLOCATOR.lock();
+        (
+            heap.stats_total_bytes(),
+            heap.stats_alloc_actual(),
+            heap.stats_alloc_user(),
+        )
+    };
+
+    println!("      total: {} bytes", total);
+    println!("  allocated: {} bytes", actual);
+    println!("  requested: {} bytes", user);
+    println!("  ballooned: {} pages", balloon::ballooned());
+
+    let stats = stats::stats();
+    println!("allocations: {}", stats.allocations);
+    println!("      frees: {}", stats.frees);
+    println!("     in use: {} bytes", stats.bytes);
+    println!("       peak: {} bytes", stats.peak);
+
+    #[cfg(feature = "alloc-debug")]
+    {
+        println!();
+        println!(
+            "{:<16} {:>12} {:>12} {:>12} {:>12}",
+            "TAG", "ALLOCS", "OUTSTANDING", "BYTES", "PEAK"
+        );
+        for (tag, stats) in stats::tags() {
+            println!(
+                "{:<16} {:>12} {:>12} {:>12} {:>12}",
+                tag,
+                stats.allocations,
+                stats.outstanding(),
+                stats.bytes,
+                stats.peak
+            );
+        }
+    }
+}
+
+fn frames(_: &[&str]) {
+    for (owner, count) in frame::usage().iter() {
+        println!("{:>16}: {} frames", owner, count);
+    }
+    println!("{:>16}: {} frames", "free", frame::free_frames());
+}
+
+fn grants(_: &[&str]) {
+    println!("{:>6} {:>6} {:>10} {:>6}", "REF", "DOMID", "FRAME", "FLAGS");
+    for (reference, entry) in grant_table::active() {
+        println!(
+            "{:>6} {:>6} {:>#10x} {:>#6x}",
+            reference, entry.domid, entry.frame, entry.flags
+        );
+    }
+}
+
+fn xs_read(args: &[&str]) {
+    match args {
+        [key] => match xenstore::read(format!("{}\0", key)) {
+            Ok(value) => println!("{}", value),
+            Err(e) => println!("error: {}", e),
+        },
+        _ => println!("usage: xs-read <key>"),
+    }
+}
+
+fn xs_write(args: &[&str]) {
+    match args {
+        [key, value] => {
+            if let Err(e) = xenstore::write(format!("{}\0", key), value) {
+                println!("error: {}", e);
+            }
+        }
+        _ => println!("usage: xs-write <key> <value>"),
+    }
+}
+
+fn xs_ls(args: &[&str]) {
+    match args {
+        [key] => match xenstore::ls(format!("{}\0", key)) {
+            Ok(children) => {
+                for child in children {
+                    println!("{}", child);
+                }
+            }
+            Err(e) => println!("error: {}", e),
+        },
+        _ => println!("usage: xs-ls <key>"),
+    }
+}
+
+fn network(_: &[&str]) {
+    match &*net::STATUS.lock() {
+        Some(status) => {
+            println!("       mac: {}", status.mac);
+            for cidr in &status.ip_addrs {
+                println!("      addr: {}", cidr);
+            }
+            println!("    tcp:80: {}", status.tcp);
+            match status.syslog {
+                Some(collector) => println!("    syslog: {}", collector),
+                None => println!("    syslog: disabled"),
+            }
+        }
+        None => println!("network is not up"),
+    }
+}
+
+fn log_level(args: &[&str]) {
+    match args {
+        [level] => match LevelFilter::from_str(level) {
+            Ok(level) => log::set_max_level(level),
+            Err(_) => println!("unknown log level {:?}", level),
+        },
+        _ => println!("log level: {}", log::max_level()),
+    }
+}
+
+fn reboot(_: &[&str]) {
+    control::shutdown(Request::Reboot);
+}
+
+fn poweroff(_: &[&str]) {
+    control::shutdown(Request::Poweroff);
+}
@@ -0,0 +1,130 @@
+//! Symmetric multiprocessing
+//!
+//! Every vCPU online in XenStore is started after the boot vCPU has initialised the kernel, and runs
+//! an executor polling shared tasks until the executors are stopped. Before suspending, secondary vCPUs
+//! are parked by taking themselves down once their current polls complete, and are brought up again
+//! or restarted afterwards. A debugger stop only pauses the vCPU that trapped.
+
+use {
+    crate::{
+        executor::{self, Executor},
+        mm::guard,
+        trap,
+    },
+    alloc::vec::Vec,
+    log::{debug, info, warn},
+    spin::Mutex,
+    xen::{
+        events,
+        platform::fpu,
+        scheduler::{schedule_operation, Command},
+        suspend::Resume,
+        time, vcpu,
+    },
+};
+
+/// IDs of the secondary vCPUs that have been started
+static STARTED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Starts all secondary vCPUs
+pub fn init() {
+    let mut started = STARTED.lock();
+
+    // vCPU 0 is already running
+    for id in vcpu::online().into_iter().filter(|id| *id > 0) {
+        let result = vcpu::start(id, secondary);
+
+        // the stacks are reused if the vCPU is restarted, so their guard pages are only registered once
+        if let Some((stack, event)) = vcpu::stack_guards(id) {
+            guard::register("vCPU", stack);
+            guard::register("vCPU event", event);
+        }
+
+        match result {
+            Ok(()) => started.push(id),
+            Err(e) => warn!("failed to start vCPU {}: {}", id, e),
+        }
+    }
+
+    info!("{} vCPU(s) online", started.len() + 1);
+}
+
+/// Takes all secondary vCPUs down once their current polls complete, as their state is not saved when suspending
+///
+/// Must be called on vCPU 0.
+pub fn park() {
+    executor::park(true);
+
+    for id in STARTED.lock().iter() {
+        // each secondary takes itself down once its executor returns
+        while vcpu::is_up(*id).unwrap_or(false) {
+            schedule_operation(Command::Yield);
+        }
+    }
+
+    debug!("secondary vCPUs parked");
+}
+
+/// Brings secondary vCPUs up again after `park`, restarting them if resuming in a new domain
+pub fn unpark(resume: Resume) {
+    executor::park(false);
+
+    for id in STARTED.lock().iter() {
+        let result = match resume {
+            Resume::Cancelled => vcpu::up(*id),
+            // per-vCPU hypervisor state is lost, except VIRQ bindings which are restored by `events::resume`
+            Resume::NewDomain => vcpu::start(*id, restarted),
+        };
+
+        if let Err(e) = result {
+            warn!("failed to bring vCPU {} up: {}", id, e);
+        }
+    }
+}
+
+/// Entrypoint of secondary vCPUs
+extern "C" fn secondary(id: usize) -> ! {
+    init_cpu();
+    time::init();
+
+    debug!("vCPU {} started", id);
+
+    run(id)
+}
+
+/// Entrypoint of secondary vCPUs restarted after resuming in a new domain
+extern "C" fn restarted(id: usize) -> ! {
+    init_cpu();
+
+    debug!("vCPU {} restarted", id);
+
+    run(id)
+}
+
+/// Registers the per-vCPU hypervisor state of the current vCPU
+fn init_cpu() {
+    trap::init();
+    fpu::init();
+    events::init_cpu();
+}
+
+/// Polls shared tasks, taking the vCPU down whenever the executors are parked
+fn run(id: usize) -> ! {
+    let mut executor = Executor::new();
+
+    loop {
+        executor.run_until_stopped();
+
+        if !executor::parked() {
+            break;
+        }
+
+        // returns if brought up again without resuming in a new domain
+        vcpu::down(id).expect("Failed to take vCPU down");
+    }
+
+    // the executor was stopped, wait for the domain to shut down
+    loop {
+        schedule_operation(Command::Block);
+    }
+}
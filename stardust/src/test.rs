@@ -1,10 +1,17 @@
 use {
+    crate::mm::guard::{self, RED_ZONE_SIZE},
     alloc::{format, vec::Vec},
     log::{debug, error},
-    xen::{grant_table, memory, xenstore},
+    xen::{
+        grant_table,
+        memory::{self, VirtualAddress},
+        platform::{consts::PAGE_SIZE, util::irq_stack_guard},
+        sections::stack_guard,
+        xenstore,
+    },
 };
 
-const TESTS: [&dyn Fn(); 3] = [&allocator, &xenstore, &grant_table];
+const TESTS: [&dyn Fn(); 4] = [&allocator, &xenstore, &grant_table, &stack_overflow];
 
 pub fn tests() {
     error!("RUNNING {} TESTS", TESTS.len());
@@ -43,16 +50,19 @@ fn xenstore() {
     xenstore::write(
         format!("/local/domain/{}/data\0", xenstore::domain_id()),
         format!("hello from domain {}!\0", xenstore::domain_id()),
-    );
+    )
+    .expect("Failed to write XenStore test value");
 
     debug!(
         "local domain contents: {:?}",
         xenstore::ls(format!("/local/domain/{}\0", xenstore::domain_id()))
+            .expect("Failed to list local domain")
     );
 
     debug!(
         "test: {:?}",
         xenstore::read(format!("/local/domain/{}/data\0", xenstore::domain_id()))
+            .expect("Failed to read XenStore test value")
     );
 }
 
@@ -64,3 +74,27 @@ fn grant_table() {
 
     debug!("max page: {:?}", memory::get_max_machine_frame_number());
 }
+
+fn stack_overflow() {
+    // the red zone is the lowest mapped page of each stack, directly above its guard page
+    for (name, guard) in [("boot", stack_guard()), ("event", irq_stack_guard())] {
+        let red_zone = guard + PAGE_SIZE;
+
+        assert_eq!(guard::overflowed(VirtualAddress(red_zone)), Some(name));
+        assert_eq!(
+            guard::overflowed(VirtualAddress(red_zone + RED_ZONE_SIZE - 8)),
+            Some(name)
+        );
+        assert_eq!(
+            guard::overflowed(VirtualAddress(red_zone + RED_ZONE_SIZE)),
+            None
+        );
+    }
+
+    // the stack pointer of a test is nowhere near overflowing
+    let local = 0u8;
+    assert_eq!(
+        guard::overflowed(VirtualAddress(&local as *const u8 as usize)),
+        None
+    );
+}
@@ -1,12 +1,26 @@
 //! Trap handlers
 
 use {
-    core::intrinsics::atomic_xchg,
+    crate::{
+        backtrace,
+        gdb::{self, Trap},
+        mm::guard,
+    },
+    core::{
+        arch::asm,
+        fmt,
+        hint::spin_loop,
+        intrinsics::atomic_xchg,
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    log::error,
     xen::{
-        dbg,
-        events::{clear_event_channel, do_event},
+        events::{clear_event_channel, cpu_mask, do_event},
         hypercall,
-        trap::{set_trap_table, TrapInfo},
+        memory::VirtualAddress,
+        platform::{backtrace::Backtrace, fpu},
+        trap::{fault_address, set_trap_table, PageFaultError, TrapFrame, TrapInfo},
+        vcpu,
         xen_sys::{__HYPERVISOR_set_callbacks, FLAT_KERNEL_CS},
         SHARED_INFO,
     },
@@ -35,18 +49,22 @@ extern "C" {
 }
 
 fn active_event_channels(idx: usize) -> u64 {
-    let shared_info = unsafe { *SHARED_INFO };
+    let shared_info = unsafe { &*SHARED_INFO };
     let pending = shared_info.evtchn_pending[idx];
     let mask = !shared_info.evtchn_mask[idx];
 
-    pending & mask
+    pending & mask & cpu_mask(idx)
 }
 
 #[no_mangle]
 /// Handler for hypervisor callback trap
-pub extern "C" fn do_hypervisor_callback() {
-    let shared_info = unsafe { *SHARED_INFO };
-    let mut vcpu_info = shared_info.vcpu_info[0];
+pub extern "C" fn do_hypervisor_callback(frame: &TrapFrame) {
+    check_stack(frame);
+
+    // event handlers may use SIMD, see `xen::platform::fpu`
+    let fpu = fpu::State::save();
+
+    let vcpu_info = unsafe { &mut *vcpu::info() };
 
     vcpu_info.evtchn_upcall_pending = 0;
 
@@ -67,16 +85,18 @@ pub extern "C" fn do_hypervisor_callback() {
 
             let event_offset = event.trailing_zeros();
 
-            let port = ((pending_selector as u32) << 5) + event_offset;
+            let port = next_event_offset * 64 + event_offset;
 
             do_event(port);
 
             clear_event_channel(port);
         }
     }
+
+    fpu.restore();
 }
 
-/// Registers the trap handlers
+/// Registers the trap handlers and event callbacks of the current vCPU
 pub fn init() {
     set_trap_table(&TRAP_TABLE);
 
@@ -202,104 +222,222 @@ static TRAP_TABLE: [TrapInfo; 18] = [
     },
 ];
 
+/// Stack overflows are reported on, as too little of the overflowed stack remains
+static mut OVERFLOW_STACK: OverflowStack = OverflowStack([0; 32768]);
+
+/// Set once a vCPU is reporting a stack overflow on `OVERFLOW_STACK`
+static OVERFLOWING: AtomicBool = AtomicBool::new(false);
+
+#[repr(C, align(16))]
+struct OverflowStack([u8; 32768]);
+
+/// Reports a stack overflow if the interrupted stack pointer is in the red zone of a stack
+fn check_stack(frame: &TrapFrame) {
+    if guard::overflowed(VirtualAddress(frame.rsp as usize)).is_none() {
+        return;
+    }
+
+    // only one vCPU can report, any other waits for the domain to crash
+    if OVERFLOWING.swap(true, Ordering::Acquire) {
+        loop {
+            spin_loop();
+        }
+    }
+
+    // SAFETY: only used by the first vCPU to overflow, and the previous stack is never returned to
+    unsafe {
+        let top = OVERFLOW_STACK.0.as_ptr().add(OVERFLOW_STACK.0.len());
+
+        asm!(
+            "mov rsp, {}",
+            "call {}",
+            in(reg) top,
+            in(reg) report_overflow as extern "C" fn(&TrapFrame) -> !,
+            in("rdi") frame,
+            options(noreturn)
+        )
+    }
+}
+
+/// Reports the stack overflow interrupted by `frame`, running on `OVERFLOW_STACK`
+extern "C" fn report_overflow(frame: &TrapFrame) -> ! {
+    let stack = guard::overflowed(VirtualAddress(frame.rsp as usize)).unwrap_or("unknown");
+
+    report(
+        "stack overflow",
+        frame,
+        format_args!("in {} stack at {:#x}", stack, frame.rsp),
+    )
+}
+
+/// Logs the register state at an unrecoverable exception then panics
+fn fatal(name: &str, frame: &TrapFrame, detail: fmt::Arguments) -> ! {
+    // reporting needs more stack than the red zone leaves
+    check_stack(frame);
+
+    report(name, frame, detail)
+}
+
+/// Logs the register state at an unrecoverable exception or stack overflow then panics
+fn report(name: &str, frame: &TrapFrame, detail: fmt::Arguments) -> ! {
+    // formatted without allocating as the fault may have occurred inside the allocator
+    error!("{} {}\n{}", name, detail, frame);
+
+    backtrace::log(Backtrace::from_frame(
+        Some(frame.rip as usize),
+        frame.rbp as usize,
+    ));
+
+    panic!("{} at {:#x}", name, frame.rip);
+}
+
 #[no_mangle]
 /// Handler for divide error trap
-pub extern "C" fn do_divide_error() {
-    dbg!()
+pub extern "C" fn do_divide_error(frame: &TrapFrame, _: u64) {
+    fatal("divide error", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for debug trap
-pub extern "C" fn do_debug() {
-    dbg!()
+pub extern "C" fn do_debug(frame: &mut TrapFrame, _: u64) {
+    check_stack(frame);
+
+    if !gdb::trap(frame, Trap::Debug) {
+        fatal("debug", frame, format_args!(""))
+    }
 }
 
 #[no_mangle]
 /// Handler for int3 trap
-pub extern "C" fn do_int3() {
-    dbg!()
+pub extern "C" fn do_int3(frame: &mut TrapFrame, _: u64) {
+    check_stack(frame);
+
+    if !gdb::trap(frame, Trap::Breakpoint) {
+        fatal("breakpoint", frame, format_args!(""))
+    }
 }
 
 #[no_mangle]
 /// Handler for overflow trap
-pub extern "C" fn do_overflow() {
-    dbg!()
+pub extern "C" fn do_overflow(frame: &TrapFrame, _: u64) {
+    fatal("overflow", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for bounds trap
-pub extern "C" fn do_bounds() {
-    dbg!()
+pub extern "C" fn do_bounds(frame: &TrapFrame, _: u64) {
+    fatal("bound range exceeded", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for invalid operation trap
-pub extern "C" fn do_invalid_op() {
-    dbg!()
+pub extern "C" fn do_invalid_op(frame: &TrapFrame, _: u64) {
+    fatal("invalid opcode", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for device not available trap
-pub extern "C" fn do_device_not_available() {
-    dbg!()
+pub extern "C" fn do_device_not_available(frame: &TrapFrame, _: u64) {
+    check_stack(frame);
+
+    // only raised if CR0.TS was set, no state is switched lazily so clear it and retry the instruction
+    fpu::clear_task_switched();
 }
 
 #[no_mangle]
 /// Handler for coprocessor segment overrun trap
-pub extern "C" fn do_coprocessor_segment_overrun() {
-    dbg!()
+pub extern "C" fn do_coprocessor_segment_overrun(frame: &TrapFrame, _: u64) {
+    fatal("coprocessor segment overrun", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for invalid TSS trap
-pub extern "C" fn do_invalid_TSS() {
-    dbg!()
+pub extern "C" fn do_invalid_TSS(frame: &TrapFrame, error_code: u64) {
+    fatal(
+        "invalid TSS",
+        frame,
+        format_args!("error code {:#x}", error_code),
+    )
 }
 
 #[no_mangle]
 /// Handler for segment not present trap
-pub extern "C" fn do_segment_not_present() {
-    dbg!()
+pub extern "C" fn do_segment_not_present(frame: &TrapFrame, error_code: u64) {
+    fatal(
+        "segment not present",
+        frame,
+        format_args!("error code {:#x}", error_code),
+    )
 }
 
 #[no_mangle]
 /// Handler for do stack segment trap
-pub extern "C" fn do_stack_segment() {
-    dbg!()
+pub extern "C" fn do_stack_segment(frame: &TrapFrame, error_code: u64) {
+    fatal(
+        "stack segment fault",
+        frame,
+        format_args!("error code {:#x}", error_code),
+    )
 }
 
 #[no_mangle]
 /// Handler for general protection trap
-pub extern "C" fn do_general_protection() {
-    dbg!()
+pub extern "C" fn do_general_protection(frame: &TrapFrame, error_code: u64) {
+    fatal(
+        "general protection fault",
+        frame,
+        format_args!("error code {:#x}", error_code),
+    )
 }
 
 #[no_mangle]
 /// Handler for page fault trap
-pub extern "C" fn do_page_fault() {
-    dbg!()
+pub extern "C" fn do_page_fault(frame: &TrapFrame, error_code: u64) {
+    let address = fault_address();
+
+    if let Some(stack) = guard::find(VirtualAddress(address)) {
+        fatal(
+            "stack overflow",
+            frame,
+            format_args!("in {} stack at {:#x}", stack, address),
+        );
+    }
+
+    fatal(
+        "page fault",
+        frame,
+        format_args!(
+            "at {:#x}, {:?}",
+            address,
+            PageFaultError::from_bits_truncate(error_code)
+        ),
+    )
 }
 
 #[no_mangle]
 /// Handler for dspurious interrupt trap
-pub extern "C" fn do_spurious_interrupt_bug() {
-    dbg!()
+pub extern "C" fn do_spurious_interrupt_bug(frame: &TrapFrame, _: u64) {
+    fatal("spurious interrupt", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for coprocessor error trap
-pub extern "C" fn do_coprocessor_error() {
-    dbg!()
+pub extern "C" fn do_coprocessor_error(frame: &TrapFrame, _: u64) {
+    fatal("x87 floating point error", frame, format_args!(""))
 }
 
 #[no_mangle]
 /// Handler for alignment check trap
-pub extern "C" fn do_alignment_check() {
-    dbg!()
+pub extern "C" fn do_alignment_check(frame: &TrapFrame, error_code: u64) {
+    fatal(
+        "alignment check",
+        frame,
+        format_args!("error code {:#x}", error_code),
+    )
 }
 
 #[no_mangle]
 /// Handler for SIMD coprocessor trap
-pub extern "C" fn do_simd_coprocessor_error() {
-    dbg!()
+pub extern "C" fn do_simd_coprocessor_error(frame: &TrapFrame, _: u64) {
+    fatal("SIMD floating point error", frame, format_args!(""))
 }
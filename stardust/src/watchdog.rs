@@ -0,0 +1,72 @@
+//! Hypervisor watchdog
+//!
+//! Enabled with `watchdog=<seconds>` on the command line. The watchdog is poked from a task at half the
+//! timeout, and is not poked while a task on any vCPU has been polled for longer than
+//! `watchdog.stall=<milliseconds>` (defaulting to half the timeout), or failed to yield within it and has not
+//! since yielded normally, so a hung kernel is restarted by Xen.
+
+use {
+    crate::{config, executor},
+    core::{mem, time::Duration},
+    log::{error, info, warn},
+    xen::{scheduler::Watchdog, Delay},
+};
+
+/// Watchdog task, pokes the watchdog while the executor is not stalled
+pub async fn task() {
+    let timeout = match config::get("watchdog").map(str::parse::<u32>) {
+        Some(Ok(timeout)) if timeout > 0 => timeout,
+        Some(_) => {
+            warn!("invalid watchdog timeout, watchdog disabled");
+            return;
+        }
+        None => return,
+    };
+
+    let period = Duration::from_secs(timeout.into()) / 2;
+
+    let threshold = match config::get("watchdog.stall").map(str::parse::<u64>) {
+        Some(Ok(ms)) => Duration::from_millis(ms),
+        Some(Err(_)) => {
+            warn!("invalid watchdog stall threshold, using {:?}", period);
+            period
+        }
+        None => period,
+    };
+
+    executor::set_stall_threshold(threshold.as_nanos() as u64);
+
+    let mut watchdog = match Watchdog::new(timeout) {
+        Ok(watchdog) => watchdog,
+        Err(e) => {
+            warn!("failed to arm watchdog: {}", e);
+            return;
+        }
+    };
+
+    info!("watchdog armed with {}s timeout", timeout);
+
+    loop {
+        Delay::new(period).await;
+
+        // the watchdog stays armed, so expires unless the task yields normally again
+        if let Some(stall) = executor::stall() {
+            error!(
+                "task {} ({}) stalled the executor, not poking watchdog",
+                stall.id, stall.name
+            );
+            continue;
+        }
+
+        if let Err(e) = watchdog.poke() {
+            // timers are not preserved when resuming in a new domain
+            warn!("failed to poke watchdog, rearming: {}", e);
+
+            match Watchdog::new(timeout) {
+                // the old timer no longer exists so must not be disarmed
+                Ok(new) => mem::forget(mem::replace(&mut watchdog, new)),
+                Err(e) => warn!("failed to rearm watchdog: {}", e),
+            }
+        }
+    }
+}
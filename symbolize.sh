@@ -0,0 +1,20 @@
+#!/usr/bin/env bash
+
+# Resolves backtrace addresses in console output read from stdin against the kernel ELF in $1
+
+if [ -z "$1" ]; then
+    echo "usage: $0 <kernel ELF>" >&2
+    exit 1
+fi
+
+while IFS= read -r line; do
+    echo "$line"
+
+    # backtrace lines are of the form `  #<n> <address>`
+    if [[ $line =~ \#[0-9]+\ (0x[0-9a-f]+) ]]; then
+        # return addresses point after the call, so resolve the preceding byte
+        address=$(printf "%#x" $((BASH_REMATCH[1] - 1)))
+
+        addr2line --exe "$1" --functions --demangle --inlines --pretty-print "$address" | sed 's/^/        /'
+    fi
+done
@@ -5,8 +5,12 @@
 #include <xen/sched.h>
 #include <xen/xen.h>
 #include <xen/grant_table.h>
+#include <xen/memory.h>
 #include <xen/io/netif.h>
 #include <xen/io/ring.h>
 #include <xen/io/console.h>
 #include <xen/io/xenbus.h>
 #include <xen/io/xs_wire.h>
+#include <xen/vcpu.h>
+#include <xen/version.h>
+#include <xen/physdev.h>
@@ -7,9 +7,11 @@ SECTIONS
   _text = .;			/* Text and read-only data */
   .text : {
 	*(.text)
+	*(.text.*)
 	*(.gnu.warning)
 	} = 0x9090
 
+  . = ALIGN(4096);		/* Text is mapped separately from read-only data */
   _etext = .;			/* End of text section */
 
   .rodata : { *(.rodata) *(.rodata.*) }
@@ -45,6 +47,7 @@ SECTIONS
 
   .data : {			/* Data */
 	*(.data)
+	*(.data.*)
 	}
 
   _edata = .;			/* End of data section */
@@ -52,9 +55,12 @@ SECTIONS
   __bss_start = .;		/* BSS */
   .bss : {
 	*(.bss)
+	*(.bss.*)
         *(.app.bss)
 
         . = ALIGN(4096) ;
+        __STACK_GUARD = . ;
+        . += 4096; /* Guard page, unmapped to catch stack overflows */
         __STACK_START = . ;
         . += 65536; /* Defines stack size, must be power of 2 */
         __STACK_END = . ;
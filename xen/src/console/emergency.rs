@@ -0,0 +1,39 @@
+//! Emergency console output
+//!
+//! Output is sent directly to the hypervisor's console (read with `xl dmesg`) through the
+//! `console_io` hypercall rather than the console ring. It is usable before the console ring is
+//! initialised and when it is wedged, but is only printed by debug builds of Xen or when Xen is
+//! booted with `guest_loglvl=all`.
+
+use {
+    crate::{
+        hypercall,
+        xen_sys::{__HYPERVISOR_console_io, CONSOLEIO_write},
+    },
+    core::fmt,
+};
+
+/// Writer sending output through the `console_io` hypercall
+pub struct EmergencyWriter;
+
+impl EmergencyWriter {
+    /// Write bytes to the hypervisor's console
+    pub fn write_bytes(bytes: &[u8]) {
+        // nothing can be done if this fails as there is nowhere left to report it
+        let _ = unsafe {
+            hypercall!(
+                __HYPERVISOR_console_io,
+                CONSOLEIO_write,
+                bytes.len() as u64,
+                bytes.as_ptr() as u64
+            )
+        };
+    }
+}
+
+impl fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        Self::write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
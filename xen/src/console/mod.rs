@@ -0,0 +1,258 @@
+//! Console utilities
+
+use {
+    crate::{
+        events::event_channel_op,
+        memory::{MachineFrameNumber, VirtualAddress},
+        scheduler::{schedule_operation, Command},
+        sync::IrqSafeMutex,
+        time::get_monotonic_time,
+        vcpu,
+        xen_sys::{evtchn_port_t, evtchn_send, start_info_t, xencons_interface, EVTCHNOP_send},
+    },
+    core::{
+        convert::TryInto,
+        fmt,
+        hint::spin_loop,
+        sync::atomic::{fence, AtomicUsize, Ordering},
+    },
+};
+
+pub use {
+    emergency::EmergencyWriter,
+    reader::{ReadLine, Reader},
+};
+
+mod emergency;
+mod reader;
+
+/// Nanoseconds the console ring can remain full before output falls back to the `EmergencyWriter`
+const RING_FULL_TIMEOUT: u64 = 1_000_000_000;
+
+/// Global Xen console writer
+static WRITER: IrqSafeMutex<Option<Writer>> = IrqSafeMutex::new(None);
+
+/// ID of the vCPU printing with `WRITER`, `NO_OWNER` if none is
+static WRITER_OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+
+/// Value of `WRITER_OWNER` while no vCPU is printing
+const NO_OWNER: usize = usize::MAX;
+
+/// Xen console writer
+pub struct Writer<'a> {
+    console: &'a mut xencons_interface,
+    console_evt: evtchn_port_t,
+    /// Whether the ring has timed out without being drained, output immediately falls back to the `EmergencyWriter` until it is
+    wedged: bool,
+}
+
+impl<'a> Writer<'a> {
+    /// Initialize the global Xen console writer
+    pub fn init(start: &start_info_t) {
+        // this might be unnecessary, re-initializing shouldn't break anything
+        // but it also shouldn't ever need to be done
+        if WRITER.lock().is_some() {
+            panic!("WRITER already initialized");
+        }
+
+        // SAFETY: `start` is a valid reference and the OS is
+        // running in an unprivileged (non-Dom0) domain
+        let dom_u = unsafe { start.console.domU };
+
+        let console = {
+            let virt = VirtualAddress::from(MachineFrameNumber(
+                dom_u
+                    .mfn
+                    .try_into()
+                    .expect("Failed to convert u64 to usize"),
+            ));
+
+            // SAFETY: will be a valid pointer for the lifetime of the instance
+            unsafe { &mut *(virt.0 as *mut xencons_interface) }
+        };
+
+        *WRITER.lock() = Some(Writer {
+            console,
+            console_evt: dom_u.evtchn,
+            wedged: false,
+        });
+    }
+
+    /// Update the console event channel from the start info structure after resuming in a new domain
+    pub(crate) fn resume(start: &start_info_t) {
+        if let Some(w) = WRITER.lock().as_mut() {
+            // SAFETY: `start` is a valid reference and the OS is
+            // running in an unprivileged (non-Dom0) domain
+            w.console_evt = unsafe { start.console.domU }.evtchn;
+        }
+
+        Reader::resume(start);
+    }
+
+    /// Yield until all data has been written, giving up if the ring is not drained within `RING_FULL_TIMEOUT`
+    ///
+    /// Does nothing if the writer is not initialized or is currently in use.
+    pub fn flush() {
+        if let Some(Some(w)) = WRITER.try_lock().as_deref_mut() {
+            let deadline = get_monotonic_time() + RING_FULL_TIMEOUT;
+
+            while (w.console).out_cons < (w.console).out_prod && get_monotonic_time() < deadline {
+                schedule_operation(Command::Yield);
+                fence(Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        let timeout = if self.wedged { 0 } else { RING_FULL_TIMEOUT };
+        let mut stalled_since = None;
+
+        while bytes.len() > 0 {
+            let sent = self.xencons_write_bytes(bytes);
+            self.event_send();
+            bytes = &bytes[sent..];
+
+            if sent > 0 {
+                self.wedged = false;
+                stalled_since = None;
+                continue;
+            }
+
+            // the ring is full, fall back to the hypervisor console if it is not drained in time
+            let now = get_monotonic_time();
+            let since = *stalled_since.get_or_insert(now);
+
+            if now - since >= timeout {
+                self.wedged = true;
+                EmergencyWriter::write_bytes(bytes);
+                return;
+            }
+        }
+
+        self.event_send();
+    }
+
+    fn xencons_write_bytes(&mut self, bytes: &[u8]) -> usize {
+        let mut sent = 0;
+
+        let intf = &mut self.console;
+
+        let mut prod = intf.out_prod;
+
+        fence(Ordering::SeqCst);
+
+        while (sent < bytes.len()) && (((prod - intf.out_cons) as usize) < intf.out.len()) {
+            intf.out[((prod) & (intf.out.len() as u32 - 1)) as usize] = bytes[sent] as i8;
+            prod += 1;
+            sent += 1;
+        }
+
+        fence(Ordering::SeqCst);
+
+        intf.out_prod = prod;
+
+        sent
+    }
+
+    fn event_send(&self) {
+        let mut op = evtchn_send {
+            port: self.console_evt,
+        };
+
+        event_channel_op(EVTCHNOP_send, &mut op as *mut _ as u64);
+    }
+}
+
+impl<'a> fmt::Write for Writer<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Prints and returns the value of a given expression for quick and dirty debugging
+#[macro_export]
+macro_rules! dbg {
+    () => {
+        $crate::println!("[{}:{}]", core::file!(), core::line!());
+    };
+    ($val:expr $(,)?) => {
+        match $val {
+            tmp => {
+                $crate::println!("[{}:{}] {} = {:#?}",
+                core::file!(), core::line!(), core::stringify!($val), &tmp);
+                tmp
+            }
+        }
+    };
+    ($($val:expr),+ $(,)?) => {
+        ($($crate::dbg!($val)),+,)
+    };
+}
+
+/// Prints to the Xen console with newline and carriage return
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n\r"));
+    ($($arg:tt)*) => ($crate::print!("{}\n\r", format_args!($($arg)*)));
+}
+
+/// Prints to the Xen console
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+/// Prints to the hypervisor's console with newline and carriage return, bypassing the console ring
+#[macro_export]
+macro_rules! emergency_println {
+    () => ($crate::emergency_print!("\n\r"));
+    ($($arg:tt)*) => ($crate::emergency_print!("{}\n\r", format_args!($($arg)*)));
+}
+
+/// Prints to the hypervisor's console, bypassing the console ring
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::console::_emergency_print(format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    // with the `console-io` feature the hypervisor's console is the primary sink
+    if cfg!(feature = "console-io") {
+        return _emergency_print(args);
+    }
+
+    let id = vcpu::id();
+
+    loop {
+        if let Some(mut writer) = WRITER.try_lock() {
+            WRITER_OWNER.store(id, Ordering::Relaxed);
+
+            match writer.as_mut() {
+                Some(w) => w.write_fmt(format_args!("{}\0", args)).unwrap(),
+                None => _emergency_print(args),
+            }
+
+            WRITER_OWNER.store(NO_OWNER, Ordering::Relaxed);
+            return;
+        }
+
+        // printing from a trap that interrupted a print on this vCPU would otherwise deadlock, so fall
+        // back as if the writer were not yet initialized
+        if WRITER_OWNER.load(Ordering::Relaxed) == id {
+            return _emergency_print(args);
+        }
+
+        // held by another vCPU, which will release it once its print completes
+        spin_loop();
+    }
+}
+
+#[doc(hidden)]
+pub fn _emergency_print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    EmergencyWriter.write_fmt(args).unwrap();
+}
@@ -0,0 +1,201 @@
+//! Console input
+
+use {
+    crate::{
+        events::{bind_event_channel, event_channel_op},
+        memory::{MachineFrameNumber, VirtualAddress},
+        print, println,
+        sync::IrqSafeMutex,
+        xen_sys::{evtchn_port_t, evtchn_send, start_info_t, xencons_interface, EVTCHNOP_send},
+    },
+    alloc::{string::String, vec::Vec},
+    core::{
+        convert::TryInto,
+        future::Future,
+        mem,
+        pin::Pin,
+        sync::atomic::{fence, Ordering},
+        task::{Context, Poll, Waker},
+    },
+};
+
+/// ASCII backspace
+const BACKSPACE: u8 = 0x08;
+
+/// ASCII delete, sent by most terminals for the backspace key
+const DELETE: u8 = 0x7F;
+
+/// Global Xen console reader
+static READER: IrqSafeMutex<Option<Reader>> = IrqSafeMutex::new(None);
+
+/// Waker of the task waiting for console input
+static WAKER: IrqSafeMutex<Option<Waker>> = IrqSafeMutex::new(None);
+
+/// Xen console reader
+pub struct Reader {
+    console: *mut xencons_interface,
+    console_evt: evtchn_port_t,
+    /// Line currently being edited
+    line: Vec<u8>,
+    /// Whether the previous byte was a carriage return, so a following line feed does not end another line
+    after_cr: bool,
+}
+
+// Required due to the raw mutable pointer to the console interface not being Send, this is safe as the virtual address it refers to is constant for the lifetime of the kernel
+unsafe impl Send for Reader {}
+
+impl Reader {
+    /// Initialize the global Xen console reader
+    ///
+    /// Binds a handler to the console event channel so must be called after `events::init`.
+    pub fn init(start: &start_info_t) {
+        if READER.lock().is_some() {
+            panic!("READER already initialized");
+        }
+
+        // SAFETY: `start` is a valid reference and the OS is
+        // running in an unprivileged (non-Dom0) domain
+        let dom_u = unsafe { start.console.domU };
+
+        let console = VirtualAddress::from(MachineFrameNumber(
+            dom_u
+                .mfn
+                .try_into()
+                .expect("Failed to convert u64 to usize"),
+        ))
+        .0 as *mut xencons_interface;
+
+        *READER.lock() = Some(Reader {
+            console,
+            console_evt: dom_u.evtchn,
+            line: Vec::new(),
+            after_cr: false,
+        });
+
+        bind_event_channel(dom_u.evtchn, handle_event, 0);
+    }
+
+    /// Update the console event channel and bind the handler again after resuming in a new domain
+    pub(crate) fn resume(start: &start_info_t) {
+        let mut reader = READER.lock();
+
+        if let Some(r) = reader.as_mut() {
+            // SAFETY: `start` is a valid reference and the OS is
+            // running in an unprivileged (non-Dom0) domain
+            r.console_evt = unsafe { start.console.domU }.evtchn;
+            bind_event_channel(r.console_evt, handle_event, 0);
+        }
+    }
+
+    /// Reads any available input into `buf` without blocking, returning the number of bytes read
+    pub fn read(buf: &mut [u8]) -> usize {
+        match *READER.lock() {
+            Some(ref mut r) => r.read_bytes(buf),
+            None => panic!("READER not initialized"),
+        }
+    }
+
+    /// Reads a line of input, waking when the console event channel is notified
+    ///
+    /// Input is echoed back to the console. Backspace removes the previous character, and CR, LF
+    /// and CR LF are all treated as a single line ending. Non-printable characters are discarded.
+    pub fn read_line() -> ReadLine {
+        ReadLine
+    }
+
+    fn read_bytes(&mut self, buf: &mut [u8]) -> usize {
+        // SAFETY: will be a valid pointer for the lifetime of the instance
+        let intf = unsafe { &mut *self.console };
+
+        let mut cons = intf.in_cons;
+        let prod = intf.in_prod;
+
+        fence(Ordering::SeqCst);
+
+        let mut read = 0;
+
+        while read < buf.len() && cons != prod {
+            buf[read] = intf.in_[(cons & (intf.in_.len() as u32 - 1)) as usize] as u8;
+            cons = cons.wrapping_add(1);
+            read += 1;
+        }
+
+        fence(Ordering::SeqCst);
+
+        intf.in_cons = cons;
+
+        if read > 0 {
+            self.event_send();
+        }
+
+        read
+    }
+
+    /// Apply a single byte of input to the current line, returning the line if it was completed
+    fn edit(&mut self, byte: u8) -> Option<String> {
+        let after_cr = mem::replace(&mut self.after_cr, byte == b'\r');
+
+        match byte {
+            b'\n' if after_cr => {}
+            b'\r' | b'\n' => {
+                println!();
+                let line = mem::take(&mut self.line);
+                return Some(String::from_utf8(line).expect("Console line contains invalid UTF-8"));
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    print!("{} {}", BACKSPACE as char, BACKSPACE as char);
+                }
+            }
+            b' '..=b'~' => {
+                self.line.push(byte);
+                print!("{}", byte as char);
+            }
+            _ => {}
+        }
+
+        None
+    }
+
+    fn event_send(&self) {
+        let mut op = evtchn_send {
+            port: self.console_evt,
+        };
+
+        event_channel_op(EVTCHNOP_send, &mut op as *mut _ as u64);
+    }
+}
+
+/// Future returned by `Reader::read_line`
+pub struct ReadLine;
+
+impl Future for ReadLine {
+    type Output = String;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // register before reading so that input arriving in between is not missed
+        *WAKER.lock() = Some(cx.waker().clone());
+
+        let mut reader = READER.lock();
+        let reader = reader.as_mut().expect("READER not initialized");
+
+        // consume a byte at a time so that input after the end of the line is left on the ring
+        let mut byte = [0];
+        while reader.read_bytes(&mut byte) > 0 {
+            if let Some(line) = reader.edit(byte[0]) {
+                return Poll::Ready(line);
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+fn handle_event(_: evtchn_port_t, _: *mut u8, _: *mut u8) {
+    // if the lock is held then the waiting task is currently registering and will read the new input anyway
+    if let Some(mut waker) = WAKER.try_lock() {
+        if let Some(waker) = waker.take() {
+            waker.wake();
+        }
+    }
+}
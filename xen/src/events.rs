@@ -3,11 +3,19 @@
 use {
     crate::{
         hypercall,
-        platform::util::{init_events, synch_clear_bit, synch_set_bit},
-        println, SHARED_INFO,
+        platform::util::{synch_clear_bit, synch_set_bit},
+        println,
+        sync::IrqSafeMutex,
+        vcpu::{self, MAX_VCPUS},
+        version, SHARED_INFO,
+    },
+    core::{
+        mem,
+        sync::atomic::{compiler_fence, Ordering},
     },
     xen_sys::{
-        __HYPERVISOR_event_channel_op, evtchn_bind_virq_t, evtchn_port_t, EVTCHNOP_bind_virq,
+        __HYPERVISOR_event_channel_op, evtchn_bind_vcpu_t, evtchn_bind_virq_t, evtchn_port_t,
+        EVTCHNOP_bind_vcpu, EVTCHNOP_bind_virq,
     },
 };
 
@@ -15,11 +23,15 @@ use {
 pub const NUM_EVENT_PORTS: usize = 1024;
 
 /// Actions for each event channel
-pub static mut EVENT_ACTIONS: [EventAction; NUM_EVENT_PORTS] = [EventAction {
-    handler: DEFAULT_HANDLER,
-    data: 0,
-    count: 0,
-}; NUM_EVENT_PORTS];
+static EVENT_ACTIONS: IrqSafeMutex<[EventAction; NUM_EVENT_PORTS]> =
+    IrqSafeMutex::new([EventAction::EMPTY; NUM_EVENT_PORTS]);
+
+/// Ports delivered to each vCPU, one bit per port, all ports are initially delivered to vCPU 0
+static CPU_MASK: IrqSafeMutex<[[u64; NUM_EVENT_PORTS / 64]; MAX_VCPUS]> = IrqSafeMutex::new({
+    let mut masks = [[0; NUM_EVENT_PORTS / 64]; MAX_VCPUS];
+    masks[0] = [u64::MAX; NUM_EVENT_PORTS / 64];
+    masks
+});
 
 /// Default event handler
 pub static DEFAULT_HANDLER: fn(evtchn_port_t, *mut u8, *mut u8) =
@@ -31,25 +43,71 @@ pub struct EventAction {
     handler: fn(evtchn_port_t, *mut u8, *mut u8),
     data: usize,
     count: u32,
+    /// VIRQ and vCPU bound to the port, recorded so the binding can be recreated after resuming
+    virq: Option<(u32, u32)>,
+}
+
+impl EventAction {
+    /// Action of a port with no handler bound
+    const EMPTY: Self = Self {
+        handler: DEFAULT_HANDLER,
+        data: 0,
+        count: 0,
+        virq: None,
+    };
 }
 
 /// Initialise events, set default handler and mask all event ports
 pub fn init() {
-    for i in 0..unsafe { EVENT_ACTIONS.len() as u32 } {
+    for i in 0..NUM_EVENT_PORTS as u32 {
         mask_event_channel(i);
     }
 
-    init_events();
+    init_cpu();
+}
+
+/// Enable event delivery to the current vCPU
+pub fn init_cpu() {
+    unsafe { (*vcpu::info()).evtchn_upcall_mask = 0 };
+}
+
+/// Masks event delivery to the current vCPU, returning the previous mask to pass to `restore_upcalls`
+pub fn mask_upcalls() -> u8 {
+    let mask = unsafe { mem::replace(&mut (*vcpu::info()).evtchn_upcall_mask, 1) };
+    compiler_fence(Ordering::SeqCst);
+    mask
+}
+
+/// Restores the event mask of the current vCPU, handling any events that became pending while masked
+pub fn restore_upcalls(mask: u8) {
+    compiler_fence(Ordering::SeqCst);
 
-    unsafe { *SHARED_INFO }.vcpu_info[0].evtchn_upcall_mask = 0;
+    let info = unsafe { &mut *vcpu::info() };
+    info.evtchn_upcall_mask = mask;
+
+    compiler_fence(Ordering::SeqCst);
+
+    if mask == 0 && info.evtchn_upcall_pending != 0 {
+        // any hypercall delivers pending events on return
+        version::version();
+    }
+}
+
+/// Gets the ports delivered to the current vCPU among the 64 ports selected by `idx`
+pub fn cpu_mask(idx: usize) -> u64 {
+    CPU_MASK.lock()[vcpu::id()][idx]
 }
 
 /// Execute event on supplied channel port
 pub fn do_event(port: evtchn_port_t) {
-    unsafe {
-        EVENT_ACTIONS[port as usize].count += 1;
-        (EVENT_ACTIONS[port as usize].handler)(port, core::ptr::null_mut(), core::ptr::null_mut())
-    }
+    // released before calling the handler, which may itself bind event channels
+    let handler = {
+        let mut actions = EVENT_ACTIONS.lock();
+        actions[port as usize].count += 1;
+        actions[port as usize].handler
+    };
+
+    handler(port, core::ptr::null_mut(), core::ptr::null_mut())
 }
 
 /// Bind an event handler to an event channel
@@ -58,43 +116,109 @@ pub fn bind_event_channel(
     handler: fn(evtchn_port_t, *mut u8, *mut u8),
     data: usize,
 ) {
-    let idx = port as usize;
-
-    if unsafe { EVENT_ACTIONS[idx].handler != DEFAULT_HANDLER } {
+    let previous = mem::replace(
+        &mut EVENT_ACTIONS.lock()[port as usize],
+        EventAction {
+            handler,
+            data,
+            count: 0,
+            virq: None,
+        },
+    );
+
+    if previous.handler != DEFAULT_HANDLER {
         println!(
             "Warning: handler for port {} already registered, replacing",
             port,
         );
     }
 
-    unsafe {
-        EVENT_ACTIONS[idx].data = data;
-        EVENT_ACTIONS[idx].count = 0;
-        EVENT_ACTIONS[idx].handler = handler;
-    }
-
     unmask_event_channel(port);
 }
 
-/// Bind a handler to a VIRQ
+/// Bind a handler to a VIRQ delivered to the current vCPU
+///
+/// Per-vCPU VIRQs such as `VIRQ_TIMER` must be bound by each vCPU.
 pub fn bind_virq(
     virq: u32,
     handler: fn(evtchn_port_t, *mut u8, *mut u8),
     data: usize,
+) -> evtchn_port_t {
+    bind_virq_on(virq, vcpu::id() as u32, handler, data)
+}
+
+fn bind_virq_on(
+    virq: u32,
+    vcpu: u32,
+    handler: fn(evtchn_port_t, *mut u8, *mut u8),
+    data: usize,
 ) -> evtchn_port_t {
     let mut op = evtchn_bind_virq_t {
         virq,
-        vcpu: 0,
+        vcpu,
         port: 0,
     };
 
     event_channel_op(EVTCHNOP_bind_virq, &mut op as *mut _ as u64);
 
+    set_cpu(op.port, vcpu as usize);
+
     bind_event_channel(op.port, handler, data);
 
+    EVENT_ACTIONS.lock()[op.port as usize].virq = Some((virq, vcpu));
+
     op.port
 }
 
+/// Deliver events on the supplied port to a vCPU rather than vCPU 0
+///
+/// Only interdomain and unbound ports may be moved, VIRQs are bound to a vCPU when created.
+pub fn bind_vcpu(port: evtchn_port_t, vcpu: usize) {
+    let mut op = evtchn_bind_vcpu_t {
+        port,
+        vcpu: vcpu as u32,
+    };
+
+    event_channel_op(EVTCHNOP_bind_vcpu, &mut op as *mut _ as u64);
+
+    set_cpu(port, vcpu);
+}
+
+/// Record that events on `port` are delivered to `vcpu`
+fn set_cpu(port: evtchn_port_t, vcpu: usize) {
+    let (idx, bit) = (port as usize / 64, port % 64);
+    let mut masks = CPU_MASK.lock();
+
+    for mask in masks.iter_mut() {
+        mask[idx] &= !(1 << bit);
+    }
+    masks[vcpu][idx] |= 1 << bit;
+}
+
+/// Reset event channels after resuming in a new domain
+///
+/// All event channels are closed when a domain is saved, so every port is masked and its handler removed.
+/// VIRQs are bound again with their previous handlers, other event channels must be bound again by their owners.
+/// Only supported with secondary vCPUs down, as ports are rebound by the current vCPU.
+pub fn resume() {
+    let actions = mem::replace(
+        &mut *EVENT_ACTIONS.lock(),
+        [EventAction::EMPTY; NUM_EVENT_PORTS],
+    );
+
+    for port in 0..NUM_EVENT_PORTS as u32 {
+        mask_event_channel(port);
+        clear_event_channel(port);
+        set_cpu(port, 0);
+    }
+
+    for action in actions.iter() {
+        if let Some((virq, vcpu)) = action.virq {
+            bind_virq_on(virq, vcpu, action.handler, action.data);
+        }
+    }
+}
+
 /// Mask an event channel port
 pub fn mask_event_channel(port: evtchn_port_t) {
     unsafe { synch_set_bit(port.into(), &mut (*SHARED_INFO).evtchn_mask[0]) }
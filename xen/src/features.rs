@@ -0,0 +1,67 @@
+//! Optional hypervisor features
+//!
+//! The features supported for this domain are read from the hypervisor during `init_info`, and
+//! behaviour that differs between them, such as whether page frame numbers must be translated to
+//! machine frame numbers, is selected with `has`.
+
+use {
+    crate::version,
+    xen_sys::{
+        XENFEAT_auto_translated_physmap, XENFEAT_dom0, XENFEAT_gnttab_map_avail_bits,
+        XENFEAT_highmem_assist, XENFEAT_hvm_callback_vector, XENFEAT_hvm_pirqs,
+        XENFEAT_hvm_safe_pvclock, XENFEAT_mmu_pt_update_preserve_ad, XENFEAT_pae_pgdir_above_4gb,
+        XENFEAT_supervisor_mode_kernel, XENFEAT_writable_descriptor_tables,
+        XENFEAT_writable_page_tables, XENFEAT_NR_SUBMAPS,
+    },
+};
+
+/// Whether each feature is supported, indexed by `XENFEAT_*`, one byte per feature as in other Xen guests
+#[no_mangle]
+pub static mut xen_features: [u8; XENFEAT_NR_SUBMAPS as usize * 32] =
+    [0; XENFEAT_NR_SUBMAPS as usize * 32];
+
+/// Optional feature of the hypervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Feature {
+    /// Page tables may be written directly rather than through `mmu_update`
+    WritablePageTables = XENFEAT_writable_page_tables,
+    /// Descriptor tables may be written directly
+    WritableDescriptorTables = XENFEAT_writable_descriptor_tables,
+    /// The hypervisor translates guest physical addresses, so page frame numbers are machine frame numbers
+    AutoTranslatedPhysmap = XENFEAT_auto_translated_physmap,
+    /// The kernel runs in ring 0
+    SupervisorModeKernel = XENFEAT_supervisor_mode_kernel,
+    /// PAE page directories may be above 4GiB
+    PaePgdirAbove4gb = XENFEAT_pae_pgdir_above_4gb,
+    /// `mmu_update` preserves the accessed and dirty bits with `MMU_PT_UPDATE_PRESERVE_AD`
+    MmuPtUpdatePreserveAd = XENFEAT_mmu_pt_update_preserve_ad,
+    /// The hypervisor assists with mapping high memory
+    HighmemAssist = XENFEAT_highmem_assist,
+    /// Grant mappings may use the available bits of page table entries
+    GnttabMapAvailBits = XENFEAT_gnttab_map_avail_bits,
+    /// Events may be delivered to HVM guests through a vector
+    HvmCallbackVector = XENFEAT_hvm_callback_vector,
+    /// The PV clock is safe to use in HVM guests
+    HvmSafePvclock = XENFEAT_hvm_safe_pvclock,
+    /// HVM guests may use PIRQs delivered as event channels
+    HvmPirqs = XENFEAT_hvm_pirqs,
+    /// The domain is the control domain
+    Dom0 = XENFEAT_dom0,
+}
+
+/// Reads the supported features from the hypervisor into `xen_features`
+pub(crate) fn init() {
+    for submap in 0..XENFEAT_NR_SUBMAPS {
+        let flags = version::features(submap).expect("Failed to get supported features");
+
+        for bit in 0..32 {
+            unsafe { xen_features[(submap * 32 + bit) as usize] = ((flags >> bit) & 1) as u8 };
+        }
+    }
+}
+
+/// Whether the hypervisor supports `feature` for this domain
+pub fn has(feature: Feature) -> bool {
+    unsafe { xen_features[feature as usize] != 0 }
+}
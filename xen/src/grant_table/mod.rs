@@ -6,14 +6,15 @@ use {
     crate::{
         memory::MachineFrameNumber,
         platform::{self, consts::PAGE_SIZE},
+        sync::IrqSafeMutex,
     },
+    alloc::vec::Vec,
     core::{
         convert::TryInto,
         mem::size_of,
         sync::atomic::{fence, Ordering},
     },
     lazy_static::lazy_static,
-    spin::Mutex,
     xen_sys::{
         domid_t, grant_entry_t, grant_ref_t, GTF_accept_transfer, GTF_permit_access, GTF_readonly,
     },
@@ -32,7 +33,7 @@ const NUM_RESERVED_ENTRIES: usize = 8;
 const NUM_GRANT_ENTRIES: usize = (NUM_GRANT_FRAMES * PAGE_SIZE) / size_of::<grant_entry_t>();
 
 lazy_static! {
-    static ref GRANT_TABLE: Mutex<GrantTable> = Mutex::new(GrantTable::new());
+    static ref GRANT_TABLE: IrqSafeMutex<GrantTable> = IrqSafeMutex::new(GrantTable::new());
 }
 
 // Required due to the raw mutable pointer to the grant table not being Send, this is safe as the virtual address it refers to is constant for the lifetime of the GrantTable
@@ -53,15 +54,22 @@ impl GrantTable {
 
         let mut celf = Self { list, table };
 
-        for i in NUM_RESERVED_ENTRIES..NUM_GRANT_ENTRIES {
-            celf.put_free_entry(i as u32);
-        }
+        celf.reset();
 
         log::trace!("grant table mapped at {:p}", table);
 
         celf
     }
 
+    /// Reset the free list, invalidating all grant references
+    fn reset(&mut self) {
+        self.list = [0; NUM_GRANT_ENTRIES];
+
+        for i in NUM_RESERVED_ENTRIES..NUM_GRANT_ENTRIES {
+            self.put_free_entry(i as u32);
+        }
+    }
+
     fn put_free_entry(&mut self, reference: grant_ref_t) {
         self.list[reference as usize] = self.list[0];
         self.list[0] = reference;
@@ -132,6 +140,13 @@ impl GrantTable {
 
         self.put_free_entry(reference);
     }
+
+    fn active(&self) -> Vec<(grant_ref_t, grant_entry_t)> {
+        (NUM_RESERVED_ENTRIES..NUM_GRANT_ENTRIES)
+            .map(|idx| (idx as grant_ref_t, unsafe { *self.table.add(idx) }))
+            .filter(|(_, entry)| entry.flags != 0)
+            .collect()
+    }
 }
 
 /// Initializes grant table
@@ -139,6 +154,28 @@ pub fn init() {
     lazy_static::initialize(&GRANT_TABLE)
 }
 
+/// Unmaps the grant table before suspending
+pub fn suspend() {
+    let table = GRANT_TABLE.lock().table;
+
+    platform::grant_table::unmap::<NUM_GRANT_FRAMES>(table)
+        .expect("Failed to unmap grant table frames");
+}
+
+/// Maps the grant table again after resuming
+///
+/// If resuming in a new domain all existing grants are lost, so every grant reference is freed and must be granted again by its owner.
+pub fn resume(new_domain: bool) {
+    let mut grant_table = GRANT_TABLE.lock();
+
+    platform::grant_table::remap::<NUM_GRANT_FRAMES>(grant_table.table)
+        .expect("Failed to remap grant table frames");
+
+    if new_domain {
+        grant_table.reset();
+    }
+}
+
 /// Grants `domain` access to the supplied frame
 pub fn grant_access(domain: domid_t, frame: MachineFrameNumber, readonly: bool) -> grant_ref_t {
     GRANT_TABLE.lock().grant_access(domain, frame, readonly)
@@ -153,3 +190,8 @@ pub fn grant_transfer(domain: domid_t, frame: MachineFrameNumber) -> grant_ref_t
 pub fn grant_end(reference: grant_ref_t) {
     GRANT_TABLE.lock().grant_end(reference)
 }
+
+/// Gets the grant references currently in use along with their entries
+pub fn active() -> Vec<(grant_ref_t, grant_entry_t)> {
+    GRANT_TABLE.lock().active()
+}
@@ -6,35 +6,41 @@
 extern crate alloc;
 
 use {
-    crate::memory::{update_va_mapping, PageEntry, TLBFlushFlags, VirtualAddress},
+    crate::{
+        memory::{update_va_mapping, PageEntry, TLBFlushFlags, VirtualAddress},
+        platform::consts::L1_PROT_NX,
+    },
     core::convert::TryInto,
-    xen_sys::{domid_t, shared_info, start_info, XENFEAT_NR_SUBMAPS},
+    xen_sys::{domid_t, shared_info, start_info},
 };
 
-pub use {delay::Delay, xen_sys};
+pub use {delay::Delay, features::xen_features, xen_sys};
 
 pub mod console;
 mod delay;
 pub mod events;
+pub mod features;
 pub mod grant_table;
 pub mod hypercall;
 pub mod memory;
+pub mod multicall;
+pub mod percpu;
+pub mod physdev;
 pub mod platform;
 pub mod scheduler;
 pub mod sections;
+pub mod suspend;
+pub mod sync;
 pub mod time;
 pub mod trap;
+pub mod vcpu;
+pub mod version;
 pub mod xenbus;
 pub mod xenstore;
 
 /// Domain ID of this domain
 pub const DOMID_SELF: domid_t = 0x7FF0;
 
-/// ?
-#[no_mangle]
-pub static mut xen_features: [u8; XENFEAT_NR_SUBMAPS as usize * 32] =
-    [0; XENFEAT_NR_SUBMAPS as usize * 32];
-
 /// Xen static startup information
 #[no_mangle]
 pub static mut START_INFO: *mut start_info = core::ptr::null_mut();
@@ -43,8 +49,10 @@ pub static mut START_INFO: *mut start_info = core::ptr::null_mut();
 #[no_mangle]
 pub static mut SHARED_INFO: *mut shared_info = core::ptr::null_mut();
 
-/// Map shared info page, initialise start and shared info pointers
+/// Map shared info page, initialise start and shared info pointers and read the supported features
 pub fn init_info(start_info: *mut start_info) {
+    platform::util::init_pda();
+
     unsafe { START_INFO = start_info };
 
     memory::init_mfn_list(
@@ -54,9 +62,16 @@ pub fn init_info(start_info: *mut start_info) {
             .expect("Failed to convert u64 to usize"),
     );
 
+    map_shared_info();
+
+    features::init();
+}
+
+/// Map the shared info page described by the start info structure
+fn map_shared_info() {
     update_va_mapping(
         VirtualAddress(unsafe { SHARED_INFO } as usize),
-        PageEntry(unsafe { (*START_INFO).shared_info } as usize | 7),
+        PageEntry(unsafe { (*START_INFO).shared_info } as usize | L1_PROT_NX),
         TLBFlushFlags::INVLPG,
     )
     .expect("Failed to map shared info page");
@@ -0,0 +1,97 @@
+//! Extended MMU operations
+//!
+//! Page table frames must be pinned before use as the base pointer, and TLB flushes are requested from
+//! the hypervisor as PV guests cannot execute `invlpg` or write CR3.
+
+use {
+    crate::{
+        hypercall,
+        memory::{MachineFrameNumber, VirtualAddress},
+        DOMID_SELF,
+    },
+    core::mem,
+    xen_sys::{
+        __HYPERVISOR_mmuext_op, mmuext_op_t, MMUEXT_INVLPG_ALL, MMUEXT_INVLPG_LOCAL,
+        MMUEXT_NEW_BASEPTR, MMUEXT_PIN_L1_TABLE, MMUEXT_PIN_L2_TABLE, MMUEXT_PIN_L3_TABLE,
+        MMUEXT_PIN_L4_TABLE, MMUEXT_TLB_FLUSH_ALL, MMUEXT_TLB_FLUSH_LOCAL, MMUEXT_UNPIN_TABLE,
+    },
+};
+
+/// Level of a page table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// Table of page entries
+    L1,
+    /// Page directory
+    L2,
+    /// Page directory pointer table
+    L3,
+    /// Top level table, used as the base pointer
+    L4,
+}
+
+/// Extended MMU operation hypercall
+fn mmuext_op(cmd: u32, set_arg1: impl FnOnce(&mut mmuext_op_t)) -> Result<(), hypercall::Error> {
+    // SAFETY: all-zero is a valid operation, every field is an integer
+    let mut op: mmuext_op_t = unsafe { mem::zeroed() };
+    op.cmd = cmd;
+    set_arg1(&mut op);
+
+    unsafe {
+        hypercall!(
+            __HYPERVISOR_mmuext_op,
+            &mut op as *mut _ as u64,
+            1u64,
+            0u64,
+            DOMID_SELF
+        )
+    }?;
+
+    Ok(())
+}
+
+/// Pins a page table frame, validating it and keeping it typed as a page table until unpinned
+pub fn pin_table(level: Level, mfn: MachineFrameNumber) -> Result<(), hypercall::Error> {
+    let cmd = match level {
+        Level::L1 => MMUEXT_PIN_L1_TABLE,
+        Level::L2 => MMUEXT_PIN_L2_TABLE,
+        Level::L3 => MMUEXT_PIN_L3_TABLE,
+        Level::L4 => MMUEXT_PIN_L4_TABLE,
+    };
+
+    mmuext_op(cmd, |op| op.arg1.mfn = mfn.0 as u64)
+}
+
+/// Unpins a page table frame so that it may be reused as an ordinary page once no longer referenced
+pub fn unpin_table(mfn: MachineFrameNumber) -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_UNPIN_TABLE, |op| op.arg1.mfn = mfn.0 as u64)
+}
+
+/// Switches the current vCPU to the pinned L4 page table in `mfn`
+///
+/// # Safety
+///
+/// The new page tables must map the kernel, its stacks and all memory in use at the same addresses.
+pub unsafe fn new_baseptr(mfn: MachineFrameNumber) -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_NEW_BASEPTR, |op| op.arg1.mfn = mfn.0 as u64)
+}
+
+/// Flushes the TLB of the current vCPU
+pub fn flush_tlb_local() -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_TLB_FLUSH_LOCAL, |_| {})
+}
+
+/// Flushes the TLBs of all vCPUs
+pub fn flush_tlb_all() -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_TLB_FLUSH_ALL, |_| {})
+}
+
+/// Invalidates the TLB entry of `va` on the current vCPU
+pub fn invlpg_local(va: VirtualAddress) -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_INVLPG_LOCAL, |op| op.arg1.linear_addr = va.0 as u64)
+}
+
+/// Invalidates the TLB entry of `va` on all vCPUs
+pub fn invlpg_all(va: VirtualAddress) -> Result<(), hypercall::Error> {
+    mmuext_op(MMUEXT_INVLPG_ALL, |op| op.arg1.linear_addr = va.0 as u64)
+}
@@ -25,34 +25,142 @@
 
 use {
     crate::{
+        features::{self, Feature},
         hypercall,
         platform::consts::{L1_PAGETABLE_SHIFT, PAGE_SIZE},
-        DOMID_SELF,
+        DOMID_SELF, SHARED_INFO,
+    },
+    alloc::{
+        alloc::{alloc_zeroed, Layout},
+        vec::Vec,
     },
     bitflags::bitflags,
-    core::convert::TryInto,
+    core::{convert::TryInto, mem::size_of},
     log::warn,
+    spin::Mutex,
     xen_sys::{
         __HYPERVISOR_memory_op, __HYPERVISOR_mmu_update, __HYPERVISOR_update_va_mapping,
-        mmu_update_t,
+        mmu_update_t, xen_memory_reservation_t, xen_pfn_t, MMU_MACHPHYS_UPDATE,
     },
 };
 
+pub mod mmuext;
 pub mod page_table;
+pub mod virtual_memory;
 mod wrappers;
 
-pub use wrappers::*;
+pub use {
+    virtual_memory::{Protection, VirtualMemory},
+    wrappers::*,
+};
 
 /// Pointer to the beginning of the machine frame number list
 ///
 /// Initialized with null pointer, this is probably really bad and **must** be set to the value of the `mfn_list` field of the start info structure before being used.
 static mut MFN_LIST: *mut usize = core::ptr::null_mut();
 
+/// Value of MFN_LIST entries for page frames with no backing machine frame
+pub const INVALID_MFN: MachineFrameNumber = MachineFrameNumber(!0);
+
 /// MFN_LIST must be initialized before converting between PageFrameNumber and MachineFrameNumber
 pub(crate) fn init_mfn_list(mfn_list_addr: usize) {
     unsafe { MFN_LIST = mfn_list_addr as *mut usize }
 }
 
+/// Sets the machine frame backing a page frame in MFN_LIST
+///
+/// # Safety
+///
+/// `pfn` must be within the MFN list and the machine frame must belong to the domain, or be `INVALID_MFN` if the page frame is no longer backed.
+pub unsafe fn set_phys_to_machine(pfn: PageFrameNumber, mfn: MachineFrameNumber) {
+    *MFN_LIST.add(pfn.0) = mfn.0;
+}
+
+/// Updates the hypervisor's machine-to-physical table so that `mfn` maps to `pfn`
+pub fn update_machine_to_phys(
+    mfn: MachineFrameNumber,
+    pfn: PageFrameNumber,
+) -> Result<(), hypercall::Error> {
+    hypervisor_mmu_update(&[machine_to_phys_update(mfn, pfn)])
+}
+
+/// Request for `hypervisor_mmu_update` mapping `mfn` to `pfn` in the machine-to-physical table
+pub fn machine_to_phys_update(mfn: MachineFrameNumber, pfn: PageFrameNumber) -> mmu_update_t {
+    mmu_update_t {
+        ptr: ((mfn.0 << L1_PAGETABLE_SHIFT) | MMU_MACHPHYS_UPDATE as usize) as u64,
+        val: pfn.0 as u64,
+    }
+}
+
+/// Number of entries in a page of the MFN list or of the frame lists describing it
+const P2M_ENTRIES: usize = PAGE_SIZE / size_of::<usize>();
+
+const PAGE_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
+
+/// Frame lists describing where the MFN list resides, read by the toolstack when saving the domain
+static P2M_FRAME_LIST: Mutex<Option<P2mFrameList>> = Mutex::new(None);
+
+/// Two-level list of the machine frames containing the MFN list
+struct P2mFrameList {
+    /// Page containing the MFNs of each page in `lists`
+    list_list: *mut usize,
+    /// Pages containing the MFNs of the pages of the MFN list
+    lists: Vec<*mut usize>,
+}
+
+// Required due to the raw pointers not being Send, this is safe as the pages are allocated once and never freed
+unsafe impl Send for P2mFrameList {}
+
+/// Publishes the location of the MFN list in the shared info page so that the domain can be saved or migrated
+///
+/// Requires that the allocator be initialised. Must be called again after resuming in a new domain as the machine frames holding the MFN list will have changed.
+/// Does nothing if the physmap is translated by the hypervisor, as there is no MFN list to publish.
+pub fn build_p2m_frame_list(max_pfn: usize) {
+    if features::has(Feature::AutoTranslatedPhysmap) {
+        return;
+    }
+
+    let mut frame_list = P2M_FRAME_LIST.lock();
+
+    let frame_list = frame_list.get_or_insert_with(|| {
+        let num_lists = (max_pfn + P2M_ENTRIES * P2M_ENTRIES - 1) / (P2M_ENTRIES * P2M_ENTRIES);
+        assert!(num_lists <= P2M_ENTRIES);
+
+        P2mFrameList {
+            list_list: unsafe { alloc_zeroed(PAGE_LAYOUT) } as *mut usize,
+            lists: (0..num_lists)
+                .map(|_| unsafe { alloc_zeroed(PAGE_LAYOUT) } as *mut usize)
+                .collect(),
+        }
+    });
+
+    for (i, list) in frame_list.lists.iter().enumerate() {
+        for j in 0..P2M_ENTRIES {
+            let pfn = (i * P2M_ENTRIES + j) * P2M_ENTRIES;
+
+            if pfn >= max_pfn {
+                break;
+            }
+
+            unsafe {
+                *list.add(j) =
+                    MachineFrameNumber::from(VirtualAddress(MFN_LIST.add(pfn) as usize)).0
+            };
+        }
+
+        unsafe {
+            *frame_list.list_list.add(i) =
+                MachineFrameNumber::from(VirtualAddress(*list as usize)).0
+        };
+    }
+
+    unsafe {
+        (*SHARED_INFO).arch.pfn_to_mfn_frame_list_list =
+            MachineFrameNumber::from(VirtualAddress(frame_list.list_list as usize)).0 as _;
+        (*SHARED_INFO).arch.max_pfn = max_pfn as _;
+    }
+}
+
 /// Gives a page frame number after rounding the given address to the next page frame boundary
 pub fn pfn_up(phys: PhysicalAddress) -> PageFrameNumber {
     // no pointer arithmetic here, only usage of PFN_UP in mini-os is by passing the result of `to_phys` which casts to unsigned long
@@ -61,6 +169,10 @@ pub fn pfn_up(phys: PhysicalAddress) -> PageFrameNumber {
 
 /// Memory operation commands
 enum Command {
+    /// Increases the reservation of the specified domain
+    IncreaseReservation = 0,
+    /// Decreases the reservation of the specified domain
+    DecreaseReservation = 1,
     /// Returns the maximum machine frame number of mapped RAM in this system
     MaximumRamPage = 2,
     /// Returns the current memory reservation in pages of the specified domain
@@ -96,6 +208,70 @@ pub fn get_max_pages() -> Result<usize, hypercall::Error> {
     .map(|n| n.try_into().expect("Failed to convert u64 to usize"))
 }
 
+/// Number of extents passed to the hypervisor at once by `increase_reservation`
+const RESERVATION_BATCH: usize = 64;
+
+/// Allocates new machine frames to the current domain, filling `frames` with their numbers
+///
+/// Returns the number of frames allocated, which may be fewer than requested if the hypervisor is out of memory or the maximum reservation was reached.
+/// Does not allocate, so may be used to grow the heap.
+pub fn increase_reservation(frames: &mut [MachineFrameNumber]) -> Result<usize, hypercall::Error> {
+    let mut extents: [xen_pfn_t; RESERVATION_BATCH] = [0; RESERVATION_BATCH];
+    let mut allocated = 0;
+
+    for chunk in frames.chunks_mut(RESERVATION_BATCH) {
+        let extents = &mut extents[..chunk.len()];
+
+        let n = match unsafe { reservation_op(Command::IncreaseReservation, extents) } {
+            Ok(n) => n,
+            Err(e) if allocated == 0 => return Err(e),
+            // the frames already allocated must still be reported
+            Err(_) => break,
+        };
+
+        for (frame, extent) in chunk.iter_mut().zip(&extents[..n]) {
+            *frame = MachineFrameNumber(*extent as usize);
+        }
+
+        allocated += n;
+
+        if n < chunk.len() {
+            break;
+        }
+    }
+
+    Ok(allocated)
+}
+
+/// Returns machine frames of the current domain to the hypervisor
+///
+/// Returns the number of frames released. The frames must no longer be mapped or referenced by MFN_LIST.
+pub fn decrease_reservation(frames: &[MachineFrameNumber]) -> Result<usize, hypercall::Error> {
+    let mut extents = frames
+        .iter()
+        .map(|frame| frame.0 as xen_pfn_t)
+        .collect::<Vec<_>>();
+
+    unsafe { reservation_op(Command::DecreaseReservation, &mut extents) }
+}
+
+/// Perform a memory reservation operation on single page extents
+unsafe fn reservation_op(
+    cmd: Command,
+    extents: &mut [xen_pfn_t],
+) -> Result<usize, hypercall::Error> {
+    let mut reservation = xen_memory_reservation_t {
+        extent_start: extents.as_mut_ptr(),
+        nr_extents: extents.len() as _,
+        extent_order: 0,
+        address_bits: 0,
+        domid: DOMID_SELF,
+    };
+
+    memory_op(cmd, &mut reservation as *mut _ as u64)
+        .map(|n| n.try_into().expect("Failed to convert u64 to usize"))
+}
+
 /// Gets the maximum machine frame number of mapped RAM in this system
 pub fn get_max_machine_frame_number() -> MachineFrameNumber {
     let mfn = unsafe { memory_op(Command::MaximumRamPage, 0) }
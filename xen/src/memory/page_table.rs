@@ -7,8 +7,8 @@ use {
             VirtualAddress,
         },
         platform::consts::{
-            L1_PAGETABLE_ENTRIES, L1_PROT, PAGETABLE_LEVELS, PAGE_MASK, PAGE_PRESENT, PAGE_RW,
-            PAGE_SHIFT, PAGE_SIZE, PT_PROT,
+            L1_PAGETABLE_ENTRIES, L1_PROT_NX, PAGETABLE_LEVELS, PAGE_MASK, PAGE_NX, PAGE_PRESENT,
+            PAGE_RW, PAGE_SHIFT, PAGE_SIZE, PT_PROT,
         },
         xen_sys::{mmu_update_t, __HYPERVISOR_VIRT_START},
     },
@@ -114,7 +114,7 @@ pub unsafe fn build(
                 mmu_updates[mmu_updates_index].ptr =
                     ((pt_mfn.0 << PAGE_SHIFT) + size_of::<PageEntry>() * offset as usize) as u64;
                 mmu_updates[mmu_updates_index].val =
-                    (MachineFrameNumber::from(pfn_to_map).0 << PAGE_SHIFT | L1_PROT) as u64;
+                    (MachineFrameNumber::from(pfn_to_map).0 << PAGE_SHIFT | L1_PROT_NX) as u64;
                 mmu_updates_index += 1;
             }
         }
@@ -149,7 +149,7 @@ pub unsafe fn new_frame(
 ) {
     let pt_page = VirtualAddress::from(pt_pfn);
 
-    let mut mmu_updates = [mmu_update_t { ptr: 0, val: 0 }; 1];
+    let mut mmu_updates = [mmu_update_t { ptr: 0, val: 0 }; 2];
 
     trace!(
         "Allocating new L{} page table frame for pfn={}, prev_l_mfn={}, offset={}",
@@ -175,14 +175,13 @@ pub unsafe fn new_frame(
         as u64;
 
     mmu_updates[0].val = ((MachineFrameNumber::from(pt_pfn).0 << PAGE_SHIFT)
-        | (PT_PROT[level - 1] & !PAGE_RW)) as u64;
+        | (PT_PROT[level - 1] & !PAGE_RW | PAGE_NX)) as u64;
 
-    hypervisor_mmu_update(&mmu_updates).expect("PTE for new page table page could not be updated");
-
-    // Hook the new page table page into the hierarchy
-    mmu_updates[0].ptr =
+    // Hook the new page table page into the hierarchy, updates are applied in order so it is already read-only
+    mmu_updates[1].ptr =
         ((prev_l_mfn.0 << PAGE_SHIFT) + size_of::<PageEntry>() * offset as usize) as u64;
-    mmu_updates[0].val = (MachineFrameNumber::from(pt_pfn).0 << PAGE_SHIFT | PT_PROT[level]) as u64;
+    mmu_updates[1].val = (MachineFrameNumber::from(pt_pfn).0 << PAGE_SHIFT | PT_PROT[level]) as u64;
 
-    hypervisor_mmu_update(&mmu_updates).expect("PTE insertion into hierarchy failed");
+    // issued together rather than batched with `Multicall`, which cannot be used before the heap exists
+    hypervisor_mmu_update(&mmu_updates).expect("Failed to map new page table page");
 }
@@ -0,0 +1,404 @@
+//! Virtual memory mappings
+//!
+//! After `page_table::build` has mapped the pseudo-physical range, pages can be mapped, unmapped and
+//! reprotected at arbitrary virtual addresses. Page table frames are allocated from the heap as
+//! required and page table entries are updated in batches with `hypervisor_mmu_update`.
+//!
+//! `VirtualMemory` maps machine frames or foreign grants at addresses allocated from the demand
+//! mapped area, unmapping them when dropped.
+
+use {
+    crate::{
+        grant_table::{self, operations::GrantHandle},
+        hypercall,
+        memory::{
+            hypervisor_mmu_update, page_table::new_frame, update_va_mapping, MachineFrameNumber,
+            PageEntry, TLBFlushFlags, VirtualAddress,
+        },
+        platform::consts::{
+            DEMAND_MAP_PAGES, L1_PAGETABLE_ENTRIES, PADDR_MASK, PAGE_ACCESSED, PAGE_MASK, PAGE_NX,
+            PAGE_PRESENT, PAGE_RW, PAGE_SHIFT, PAGE_SIZE, PAGE_USER, VIRT_DEMAND_AREA,
+        },
+        START_INFO,
+    },
+    alloc::{
+        alloc::{alloc_zeroed, Layout},
+        vec,
+        vec::Vec,
+    },
+    bitflags::bitflags,
+    core::mem::size_of,
+    displaydoc::Display,
+    spin::Mutex,
+    xen_sys::{domid_t, grant_ref_t, mmu_update_t, __HYPERVISOR_VIRT_END, __HYPERVISOR_VIRT_START},
+};
+
+const PAGE_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
+
+/// Free ranges of pages in the demand mapped area as page offsets and lengths, `None` until first used
+static DEMAND_MAP: Mutex<Option<Vec<(usize, usize)>>> = Mutex::new(None);
+
+/// Virtual memory error
+#[derive(Display, Debug)]
+pub enum Error {
+    /// Virtual address {0:#x} is not page aligned
+    Unaligned(usize),
+    /// Virtual address {0:#x} is not mapped
+    NotMapped(usize),
+    /// Virtual address {0:#x} is not canonical or is reserved by the hypervisor
+    Reserved(usize),
+    /// No free virtual address space for {0} pages
+    OutOfAddressSpace(usize),
+    /// Failed to allocate a page table frame
+    OutOfMemory,
+    /// Hypercall failed updating page tables: {0}
+    Hypercall(hypercall::Error),
+    /// Failed to map grant: {0}
+    Grant(grant_table::Error),
+}
+
+impl From<hypercall::Error> for Error {
+    fn from(e: hypercall::Error) -> Self {
+        Self::Hypercall(e)
+    }
+}
+
+impl From<grant_table::Error> for Error {
+    fn from(e: grant_table::Error) -> Self {
+        Self::Grant(e)
+    }
+}
+
+bitflags! {
+/// Access permitted to mapped pages, pages are always readable
+pub struct Protection: u8 {
+    /// Pages may be written
+    const WRITE = 0b01;
+    /// Pages may be executed
+    const EXECUTE = 0b10;
+}}
+
+impl Protection {
+    /// Page table entry flags granting this access
+    fn flags(self) -> usize {
+        let mut flags = PAGE_PRESENT | PAGE_ACCESSED | PAGE_USER;
+
+        if self.contains(Protection::WRITE) {
+            flags |= PAGE_RW;
+        }
+
+        if !self.contains(Protection::EXECUTE) {
+            flags |= PAGE_NX;
+        }
+
+        flags
+    }
+}
+
+/// Pages mapped in the demand mapped area, unmapped when dropped
+pub struct VirtualMemory {
+    address: VirtualAddress,
+    pages: usize,
+    grants: Vec<GrantHandle>,
+}
+
+impl VirtualMemory {
+    /// Map machine frames at a newly allocated virtual address
+    pub fn map(frames: &[MachineFrameNumber], protection: Protection) -> Result<Self, Error> {
+        let address = allocate(frames.len())?;
+
+        // SAFETY: the address range was newly allocated so nothing else refers to it
+        if let Err(e) = unsafe { map(address, frames, protection) } {
+            free(address, frames.len());
+            return Err(e);
+        }
+
+        Ok(Self {
+            address,
+            pages: frames.len(),
+            grants: Vec::new(),
+        })
+    }
+
+    /// Map pages granted by `domain` at a newly allocated virtual address
+    pub fn map_grants(
+        domain: domid_t,
+        references: &[grant_ref_t],
+        readonly: bool,
+    ) -> Result<Self, Error> {
+        let address = allocate(references.len())?;
+
+        let mut memory = Self {
+            address,
+            pages: references.len(),
+            grants: Vec::with_capacity(references.len()),
+        };
+
+        for (i, reference) in references.iter().enumerate() {
+            let page = VirtualAddress(address.0 + i * PAGE_SIZE);
+
+            // Xen fills in an existing L1 page table entry
+            unsafe { l1_entry(page, true) }?;
+
+            let handle =
+                unsafe { GrantHandle::new(page.0 as *const u8, *reference, domain, readonly) }?;
+            memory.grants.push(handle);
+        }
+
+        Ok(memory)
+    }
+
+    /// First virtual address of the mapping
+    pub fn address(&self) -> VirtualAddress {
+        self.address
+    }
+
+    /// Pointer to the start of the mapping
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.address.0 as *mut T
+    }
+
+    /// Number of pages mapped
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    /// Change the access permitted to all mapped pages
+    pub fn protect(&mut self, protection: Protection) -> Result<(), Error> {
+        unsafe { protect(self.address, self.pages, protection) }
+    }
+}
+
+impl Drop for VirtualMemory {
+    fn drop(&mut self) {
+        let result = if self.grants.is_empty() {
+            unsafe { unmap(self.address, self.pages) }
+        } else {
+            self.grants
+                .drain(..)
+                .try_for_each(|handle| handle.unmap())
+                .map_err(Error::from)
+        };
+
+        match result {
+            Ok(()) => free(self.address, self.pages),
+            // leak the address range rather than reuse a possibly still mapped one
+            Err(e) => log::error!("failed to unmap {:#x}: {}", self.address.0, e),
+        }
+    }
+}
+
+/// Gets the L1 page table entry mapping `address`, `None` if any level of the page table is not present
+///
+/// Also `None` for addresses that are not canonical or are reserved by the hypervisor, so any address may
+/// be checked before it is dereferenced.
+pub fn walk(address: VirtualAddress) -> Option<PageEntry> {
+    // SAFETY: page tables are only read, and are not created if missing
+    let entry = unsafe { *l1_entry(address, false).ok()?.0 };
+
+    if entry.0 & PAGE_PRESENT == 0 {
+        return None;
+    }
+
+    Some(entry)
+}
+
+/// Map `frames` at consecutive pages starting at `address`
+///
+/// # Safety
+///
+/// Any existing mapping of the range is replaced, which must not be in use.
+pub unsafe fn map(
+    address: VirtualAddress,
+    frames: &[MachineFrameNumber],
+    protection: Protection,
+) -> Result<(), Error> {
+    let flags = protection.flags();
+
+    update(address, frames.len(), true, |i, _| {
+        Ok(PageEntry(frames[i].0 << PAGE_SHIFT | flags))
+    })
+}
+
+/// Unmap `pages` consecutive pages starting at `address`
+///
+/// # Safety
+///
+/// The range must not be in use.
+pub unsafe fn unmap(address: VirtualAddress, pages: usize) -> Result<(), Error> {
+    update(address, pages, false, |_, _| Ok(PageEntry(0)))
+}
+
+/// Change the access permitted to `pages` consecutive mapped pages starting at `address`
+///
+/// # Safety
+///
+/// Removing access to pages in use will cause a page fault when they are next accessed.
+pub unsafe fn protect(
+    address: VirtualAddress,
+    pages: usize,
+    protection: Protection,
+) -> Result<(), Error> {
+    let flags = protection.flags();
+
+    update(address, pages, false, |i, entry| {
+        if entry.0 & PAGE_PRESENT == 0 {
+            Err(Error::NotMapped(address.0 + i * PAGE_SIZE))
+        } else {
+            Ok(PageEntry(entry.0 & PADDR_MASK & PAGE_MASK | flags))
+        }
+    })
+}
+
+/// Replace the L1 entries of `pages` pages starting at `address` with the result of `f`
+///
+/// `f` is supplied with the index of the page and its current entry. Updates are issued in batches
+/// of `L1_PAGETABLE_ENTRIES`, and the TLB is flushed once all have been made.
+unsafe fn update<F>(address: VirtualAddress, pages: usize, create: bool, f: F) -> Result<(), Error>
+where
+    F: Fn(usize, PageEntry) -> Result<PageEntry, Error>,
+{
+    if address.0 & !PAGE_MASK != 0 {
+        return Err(Error::Unaligned(address.0));
+    }
+
+    let mut updates = Vec::with_capacity(pages.min(L1_PAGETABLE_ENTRIES));
+    let mut last = None;
+
+    for i in 0..pages {
+        let page = VirtualAddress(address.0 + i * PAGE_SIZE);
+        let (entry, machine_address) = l1_entry(page, create)?;
+
+        let new = f(i, *entry)?;
+
+        updates.push(mmu_update_t {
+            ptr: machine_address as u64,
+            val: new.0 as u64,
+        });
+        last = Some((page, new));
+
+        if updates.len() == L1_PAGETABLE_ENTRIES {
+            hypervisor_mmu_update(&updates)?;
+            updates.clear();
+        }
+    }
+
+    if !updates.is_empty() {
+        hypervisor_mmu_update(&updates)?;
+    }
+
+    // mmu_update does not flush, so reapply the final entry flushing the whole TLB
+    if let Some((page, entry)) = last {
+        update_va_mapping(page, entry, TLBFlushFlags::FLUSH)?;
+    }
+
+    Ok(())
+}
+
+/// Gets a pointer to the L1 entry mapping `address` and its machine address
+///
+/// Missing page tables are allocated if `create` is set, otherwise `Error::NotMapped` is returned.
+unsafe fn l1_entry(
+    address: VirtualAddress,
+    create: bool,
+) -> Result<(*mut PageEntry, usize), Error> {
+    // a non-canonical address aliases a mapped L4 slot, and the hypervisor's own L4 entries do not lead
+    // to page tables of this domain
+    let canonical = (address.0 as isize) << 16 >> 16 == address.0 as isize;
+    let hypervisor =
+        (__HYPERVISOR_VIRT_START as usize..__HYPERVISOR_VIRT_END as usize).contains(&address.0);
+    if !canonical || hypervisor {
+        return Err(Error::Reserved(address.0));
+    }
+
+    let pt_base = (*START_INFO).pt_base as *mut PageEntry;
+
+    let offsets = [
+        address.l4_table_offset(),
+        address.l3_table_offset(),
+        address.l2_table_offset(),
+    ];
+
+    let mut table = pt_base;
+
+    for (level, offset) in (2..=4).rev().zip(offsets.iter()) {
+        let entry = table.offset(*offset);
+
+        if (*entry).0 & PAGE_PRESENT == 0 {
+            if !create {
+                return Err(Error::NotMapped(address.0));
+            }
+
+            let frame = alloc_zeroed(PAGE_LAYOUT);
+            if frame.is_null() {
+                return Err(Error::OutOfMemory);
+            }
+
+            let table_mfn = MachineFrameNumber::from(VirtualAddress(table as usize));
+            new_frame(
+                pt_base,
+                VirtualAddress(frame as usize).into(),
+                table_mfn,
+                *offset,
+                level - 1,
+            );
+        }
+
+        table = VirtualAddress::from(*entry).0 as *mut PageEntry;
+    }
+
+    let offset = address.l1_table_offset();
+    let l1_mfn = MachineFrameNumber::from(VirtualAddress(table as usize));
+
+    Ok((
+        table.offset(offset),
+        (l1_mfn.0 << PAGE_SHIFT) + size_of::<PageEntry>() * offset as usize,
+    ))
+}
+
+/// Allocate `pages` consecutive pages of virtual address space from the demand mapped area
+pub fn allocate(pages: usize) -> Result<VirtualAddress, Error> {
+    let mut ranges = DEMAND_MAP.lock();
+    let ranges = ranges.get_or_insert_with(|| vec![(0, DEMAND_MAP_PAGES)]);
+
+    let index = ranges
+        .iter()
+        .position(|(_, len)| *len >= pages)
+        .ok_or(Error::OutOfAddressSpace(pages))?;
+
+    let (start, len) = ranges[index];
+
+    if len == pages {
+        ranges.remove(index);
+    } else {
+        ranges[index] = (start + pages, len - pages);
+    }
+
+    Ok(VirtualAddress(VIRT_DEMAND_AREA + start * PAGE_SIZE))
+}
+
+/// Return `pages` pages of virtual address space starting at `address` to the demand mapped area
+pub fn free(address: VirtualAddress, pages: usize) {
+    let start = (address.0 - VIRT_DEMAND_AREA) / PAGE_SIZE;
+
+    let mut ranges = DEMAND_MAP.lock();
+    let ranges = ranges
+        .as_mut()
+        .expect("Freed virtual memory that was never allocated");
+
+    let index = ranges
+        .iter()
+        .position(|(s, _)| *s > start)
+        .unwrap_or(ranges.len());
+    ranges.insert(index, (start, pages));
+
+    // merge with the following then the preceding range
+    if index + 1 < ranges.len() && start + pages == ranges[index + 1].0 {
+        ranges[index].1 += ranges[index + 1].1;
+        ranges.remove(index + 1);
+    }
+    if index > 0 && ranges[index - 1].0 + ranges[index - 1].1 == start {
+        ranges[index - 1].1 += ranges[index].1;
+        ranges.remove(index);
+    }
+}
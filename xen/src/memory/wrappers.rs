@@ -1,6 +1,7 @@
 use {
     super::MFN_LIST,
     crate::{
+        features::{self, Feature},
         platform::consts::{
             L1_PAGETABLE_ENTRIES, L1_PAGETABLE_SHIFT, L2_PAGETABLE_ENTRIES, L2_PAGETABLE_SHIFT,
             L3_PAGETABLE_ENTRIES, L3_PAGETABLE_SHIFT, L4_PAGETABLE_ENTRIES, L4_PAGETABLE_SHIFT,
@@ -23,6 +24,10 @@ pub struct PageFrameNumber(pub usize);
 // mfn_to_pfn
 impl From<MachineFrameNumber> for PageFrameNumber {
     fn from(mfn: MachineFrameNumber) -> Self {
+        if features::has(Feature::AutoTranslatedPhysmap) {
+            return Self(mfn.0);
+        }
+
         Self(unsafe {
             *(__HYPERVISOR_VIRT_START as *const usize).offset(
                 mfn.0
@@ -48,6 +53,10 @@ pub struct MachineFrameNumber(pub usize);
 // pfn_to_mfn
 impl From<PageFrameNumber> for MachineFrameNumber {
     fn from(pfn: PageFrameNumber) -> Self {
+        if features::has(Feature::AutoTranslatedPhysmap) {
+            return Self(pfn.0);
+        }
+
         Self(unsafe {
             *MFN_LIST.offset(
                 pfn.0
@@ -0,0 +1,157 @@
+//! Batched hypercalls
+//!
+//! A `Multicall` queues hypercalls and issues them in a single `__HYPERVISOR_multicall` trap, so a
+//! sequence of operations costs one VM exit rather than one each. Operations are executed in the order
+//! they were queued, and one failing does not prevent the rest from being executed.
+
+use {
+    crate::{
+        hypercall::{self, errno_to_result},
+        memory::{PageEntry, TLBFlushFlags, VirtualAddress},
+        DOMID_SELF,
+    },
+    alloc::{boxed::Box, vec::Vec},
+    core::marker::PhantomData,
+    xen_sys::{
+        __HYPERVISOR_event_channel_op, __HYPERVISOR_grant_table_op, __HYPERVISOR_mmu_update,
+        __HYPERVISOR_multicall, __HYPERVISOR_update_va_mapping, evtchn_port_t, evtchn_send,
+        gnttab_copy_t, gnttab_map_grant_ref_t, gnttab_unmap_grant_ref_t, mmu_update_t,
+        multicall_entry_t, EVTCHNOP_send, GNTTABOP_copy, GNTTABOP_map_grant_ref,
+        GNTTABOP_unmap_grant_ref,
+    },
+};
+
+/// Batch of hypercalls submitted together
+///
+/// Arguments passed by reference are borrowed until the batch is submitted, as the hypervisor only
+/// reads them then.
+#[derive(Default)]
+pub struct Multicall<'a> {
+    entries: Vec<multicall_entry_t>,
+    /// Arguments owned by the batch, boxed so their addresses do not change as more are added
+    sends: Vec<Box<evtchn_send>>,
+    _arguments: PhantomData<&'a mut ()>,
+}
+
+impl<'a> Multicall<'a> {
+    /// Creates an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of operations queued
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no operations are queued
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn push(&mut self, op: u32, args: &[u64]) -> &mut Self {
+        let mut entry = multicall_entry_t {
+            op: op.into(),
+            result: 0,
+            args: [0; 6],
+        };
+        entry.args[..args.len()].copy_from_slice(args);
+
+        self.entries.push(entry);
+        self
+    }
+
+    /// Queues updates to page table entries of this domain
+    pub fn mmu_update(&mut self, reqs: &'a [mmu_update_t]) -> &mut Self {
+        self.push(
+            __HYPERVISOR_mmu_update,
+            &[
+                reqs.as_ptr() as u64,
+                reqs.len() as u64,
+                0,
+                DOMID_SELF.into(),
+            ],
+        )
+    }
+
+    /// Queues an update of the mapping of a virtual address
+    pub fn update_va_mapping(
+        &mut self,
+        va: VirtualAddress,
+        page: PageEntry,
+        flags: TLBFlushFlags,
+    ) -> &mut Self {
+        self.push(
+            __HYPERVISOR_update_va_mapping,
+            &[va.0 as u64, page.0 as u64, flags.bits()],
+        )
+    }
+
+    /// Queues mapping of grant references, the status of each is written back to `ops`
+    pub fn grant_map(&mut self, ops: &'a mut [gnttab_map_grant_ref_t]) -> &mut Self {
+        self.push(
+            __HYPERVISOR_grant_table_op,
+            &[
+                GNTTABOP_map_grant_ref.into(),
+                ops.as_mut_ptr() as u64,
+                ops.len() as u64,
+            ],
+        )
+    }
+
+    /// Queues unmapping of grant references, the status of each is written back to `ops`
+    pub fn grant_unmap(&mut self, ops: &'a mut [gnttab_unmap_grant_ref_t]) -> &mut Self {
+        self.push(
+            __HYPERVISOR_grant_table_op,
+            &[
+                GNTTABOP_unmap_grant_ref.into(),
+                ops.as_mut_ptr() as u64,
+                ops.len() as u64,
+            ],
+        )
+    }
+
+    /// Queues copies to or from granted pages, the status of each is written back to `ops`
+    pub fn grant_copy(&mut self, ops: &'a mut [gnttab_copy_t]) -> &mut Self {
+        self.push(
+            __HYPERVISOR_grant_table_op,
+            &[
+                GNTTABOP_copy.into(),
+                ops.as_mut_ptr() as u64,
+                ops.len() as u64,
+            ],
+        )
+    }
+
+    /// Queues a notification of the remote end of an event channel
+    pub fn notify(&mut self, port: evtchn_port_t) -> &mut Self {
+        let mut op = Box::new(evtchn_send { port });
+        let ptr = &mut *op as *mut _ as u64;
+        self.sends.push(op);
+
+        self.push(__HYPERVISOR_event_channel_op, &[EVTCHNOP_send.into(), ptr])
+    }
+
+    /// Issues all queued operations, returning the result of each in the order they were queued
+    ///
+    /// Fails only if the batch itself could not be issued, in which case none of the operations were executed.
+    pub fn submit(mut self) -> Result<Vec<Result<u64, hypercall::Error>>, hypercall::Error> {
+        if self.entries.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        unsafe {
+            hypercall!(
+                __HYPERVISOR_multicall,
+                self.entries.as_mut_ptr() as u64,
+                self.entries.len() as u64
+            )
+        }?;
+
+        Ok(self
+            .entries
+            .iter()
+            .map(|entry| errno_to_result(entry.result as i64))
+            .collect())
+    }
+}
@@ -0,0 +1,45 @@
+//! Per-vCPU storage
+//!
+//! Statics declared with `percpu!` hold a separate value for each vCPU, selected by the vCPU ID in the
+//! per-CPU data area addressed through the GS base. A value is only reachable from its own vCPU, but
+//! may be accessed again by an event handler interrupting it.
+
+use crate::vcpu::{self, MAX_VCPUS};
+
+/// Declares a static holding a separate value for each vCPU, accessed with `PerCpu::get`
+///
+/// ```ignore
+/// percpu! {
+///     /// Number of events handled by each vCPU
+///     static EVENTS: Cell<u64> = Cell::new(0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::percpu::PerCpu<$ty> = {
+            const INIT: $ty = $init;
+            $crate::percpu::PerCpu::new([INIT; $crate::vcpu::MAX_VCPUS])
+        };
+    };
+}
+
+/// Value stored separately for each vCPU
+pub struct PerCpu<T>([T; MAX_VCPUS]);
+
+// Required as each vCPU only accesses its own value, so values need only be Send to be initialised by another vCPU
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Creates per-vCPU storage from the initial value of each vCPU, use `percpu!` instead
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_VCPUS]) -> Self {
+        Self(values)
+    }
+
+    /// Gets the value of the vCPU executing the caller
+    pub fn get(&self) -> &T {
+        &self.0[vcpu::id()]
+    }
+}
@@ -0,0 +1,22 @@
+//! Physical device operations
+
+use {
+    crate::hypercall,
+    xen_sys::{__HYPERVISOR_physdev_op, physdev_set_iopl_t, PHYSDEVOP_set_iopl},
+};
+
+/// Physical device operation hypercall
+unsafe fn physdev_op(cmd: u32, arg: u64) -> Result<u64, hypercall::Error> {
+    hypercall!(__HYPERVISOR_physdev_op, cmd, arg)
+}
+
+/// Sets the I/O privilege level of the current vCPU, allowing `in` and `out` at or below `level`
+///
+/// Only effective in domains granted access to I/O ports by the toolstack.
+pub fn set_iopl(level: u32) -> Result<(), hypercall::Error> {
+    let mut op = physdev_set_iopl_t { iopl: level };
+
+    unsafe { physdev_op(PHYSDEVOP_set_iopl, &mut op as *mut _ as u64) }?;
+
+    Ok(())
+}
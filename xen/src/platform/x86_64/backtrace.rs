@@ -0,0 +1,71 @@
+//! Frame pointer stack walking
+//!
+//! Each frame begins with the caller's frame pointer followed by the return address, which relies on
+//! the kernel being built with `force-frame-pointers`. Frame pointers are checked to be mapped before
+//! being followed so a corrupted stack ends the walk rather than faulting.
+
+use {
+    crate::memory::{virtual_memory::walk, VirtualAddress},
+    core::{arch::asm, mem::size_of},
+};
+
+/// Maximum number of frames walked, guarding against cycles in a corrupted chain
+const MAX_FRAMES: usize = 64;
+
+/// Iterator over the return addresses of a chain of stack frames, innermost first
+pub struct Backtrace {
+    rip: Option<usize>,
+    rbp: usize,
+    depth: usize,
+}
+
+impl Backtrace {
+    /// Walk the stack of the calling function
+    #[inline(always)]
+    pub fn current() -> Self {
+        let rbp: usize;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+        Self::from_frame(None, rbp)
+    }
+
+    /// Walk the stack from the frame pointer `rbp`, starting with `rip` if supplied
+    ///
+    /// Used to walk the interrupted context of a trap, where the faulting instruction is not a
+    /// return address in the chain.
+    pub fn from_frame(rip: Option<usize>, rbp: usize) -> Self {
+        Self { rip, rbp, depth: 0 }
+    }
+}
+
+impl Iterator for Backtrace {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if let Some(rip) = self.rip.take() {
+            return Some(rip);
+        }
+
+        if self.depth == MAX_FRAMES || self.rbp == 0 || self.rbp % size_of::<usize>() != 0 {
+            return None;
+        }
+
+        // the saved frame pointer and return address may straddle a page boundary
+        let end = self.rbp + 2 * size_of::<usize>() - 1;
+        if walk(VirtualAddress(self.rbp)).is_none() || walk(VirtualAddress(end)).is_none() {
+            return None;
+        }
+
+        let frame = self.rbp as *const usize;
+        let (rbp, address) = unsafe { (*frame, *frame.add(1)) };
+
+        if address == 0 {
+            return None;
+        }
+
+        self.rbp = rbp;
+        self.depth += 1;
+
+        Some(address)
+    }
+}
@@ -64,10 +64,15 @@ pub const PAGE_PAT: usize = 0x080;
 pub const PAGE_PSE: usize = 0x080;
 ///
 pub const PAGE_GLOBAL: usize = 0x100;
+/// Page may not be executed
+pub const PAGE_NX: usize = 1 << 63;
 
 /// L1 page flags
 pub const L1_PROT: usize = PAGE_PRESENT | PAGE_RW | PAGE_ACCESSED | PAGE_USER;
 
+/// L1 page flags for data, which may not be executed
+pub const L1_PROT_NX: usize = L1_PROT | PAGE_NX;
+
 /// L1 page flags read-only
 pub const L1_PROT_RO: usize = PAGE_PRESENT | PAGE_ACCESSED | PAGE_USER;
 
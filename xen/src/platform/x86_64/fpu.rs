@@ -0,0 +1,53 @@
+//! FPU and SSE state
+//!
+//! The kernel is built for a soft-float target, so compiled code never uses x87 or SSE registers.
+//! Code using them explicitly, with inline assembly or `#[target_feature]`, may only do so in tasks.
+//! The hypervisor callback saves and restores the interrupted state around event handlers, which may
+//! therefore also use SIMD, but trap handlers do not and must not. Tasks are switched only at await
+//! points where no register state is live, so state is not switched between tasks. AVX and later
+//! extended state is not saved and must not be used.
+
+use {crate::hypercall, core::arch::asm, xen_sys::__HYPERVISOR_fpu_taskswitch};
+
+/// x87 control word with all exceptions masked, double extended precision and round to nearest
+const FPU_CONTROL_WORD: u16 = 0x037f;
+
+/// MXCSR with all exceptions masked and round to nearest
+const MXCSR: u32 = 0x1f80;
+
+/// x87 and SSE register state as saved by `fxsave`
+#[repr(C, align(16))]
+pub struct State([u8; 512]);
+
+impl State {
+    /// Saves the current FPU and SSE registers
+    #[inline(always)]
+    pub fn save() -> Self {
+        let mut state = Self([0; 512]);
+        unsafe { asm!("fxsave64 [{}]", in(reg) state.0.as_mut_ptr(), options(nostack)) };
+        state
+    }
+
+    /// Restores the FPU and SSE registers
+    #[inline(always)]
+    pub fn restore(&self) {
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack)) };
+    }
+}
+
+/// Initialise the FPU and SSE control registers
+pub fn init() {
+    clear_task_switched();
+
+    unsafe {
+        asm!("fninit", options(nomem, nostack));
+        asm!("fldcw [{}]", in(reg) &FPU_CONTROL_WORD, options(nostack));
+        asm!("ldmxcsr [{}]", in(reg) &MXCSR, options(nostack));
+    }
+}
+
+/// Clears CR0.TS so FPU and SSE instructions no longer raise the device not available trap
+pub fn clear_task_switched() {
+    unsafe { hypercall!(__HYPERVISOR_fpu_taskswitch, 0u64) }
+        .expect("Failed to clear task switched flag");
+}
@@ -4,19 +4,16 @@ use {
     crate::{
         grant_table::{operations::setup_table, Error},
         memory::{
-            get_max_machine_frame_number, hypervisor_mmu_update, page_table::new_frame,
-            MachineFrameNumber, PageEntry, PageFrameNumber, PhysicalAddress, VirtualAddress,
+            get_max_machine_frame_number, virtual_memory, MachineFrameNumber, PageEntry,
+            Protection, TLBFlushFlags, VirtualAddress,
         },
-        platform::consts::{L1_PAGETABLE_ENTRIES, L1_PROT, PAGE_PRESENT, PAGE_SHIFT, PAGE_SIZE},
-        DOMID_SELF, START_INFO,
+        multicall::Multicall,
+        platform::consts::{L1_PROT_NX, PAGE_SHIFT, PAGE_SIZE},
+        DOMID_SELF,
     },
-    alloc::alloc::{alloc, Layout},
-    core::mem::size_of,
-    xen_sys::{grant_entry_t, mmu_update_t, __HYPERVISOR_VIRT_START},
+    xen_sys::grant_entry_t,
 };
 
-const PAGE_LAYOUT: Layout = unsafe { Layout::from_size_align_unchecked(PAGE_SIZE, PAGE_SIZE) };
-
 /// Initialize grant table
 pub fn init<const NUM_GRANT_FRAMES: usize>() -> Result<*mut grant_entry_t, Error> {
     let mut frames = [0u64; NUM_GRANT_FRAMES];
@@ -34,140 +31,54 @@ pub fn init<const NUM_GRANT_FRAMES: usize>() -> Result<*mut grant_entry_t, Error
         grant_table + NUM_GRANT_FRAMES * PAGE_SIZE
     );
 
-    unsafe {
-        build(
-            (*START_INFO).pt_base as *mut _,
-            VirtualAddress(grant_table).into(),
-            VirtualAddress(grant_table + NUM_GRANT_FRAMES * PAGE_SIZE).into(),
-            &frames,
-        )
-    };
+    let frames = frames.map(|frame| MachineFrameNumber(frame as usize));
+
+    unsafe { virtual_memory::map(VirtualAddress(grant_table), &frames, Protection::WRITE) }
+        .expect("Failed to map grant table");
 
     Ok(grant_table as *mut _)
 }
 
-unsafe fn build(
-    pt_base: *mut PageEntry,
-    start_pfn: PageFrameNumber,
-    max_pfn: PageFrameNumber,
-    frames: &[u64],
-) {
-    let mut pfn_counter = 0;
+/// Unmap the grant table frames before suspending
+pub fn unmap<const NUM_GRANT_FRAMES: usize>(table: *mut grant_entry_t) -> Result<(), Error> {
+    let mut batch = Multicall::new();
 
-    let mut mmu_updates = [mmu_update_t { ptr: 0, val: 0 }; L1_PAGETABLE_ENTRIES + 1];
-    let mut mmu_updates_index = 0;
+    for i in 0..NUM_GRANT_FRAMES {
+        batch.update_va_mapping(
+            VirtualAddress(table as usize + i * PAGE_SIZE),
+            PageEntry(0),
+            TLBFlushFlags::INVLPG,
+        );
+    }
 
-    if max_pfn >= PageFrameNumber::from(VirtualAddress(__HYPERVISOR_VIRT_START as usize)) {
-        panic!("Maximum page frame number overlaps with Xen virtual space");
+    for result in batch.submit()? {
+        result?;
     }
 
-    let start_address = VirtualAddress::from(start_pfn);
-    let end_address = VirtualAddress::from(max_pfn);
+    Ok(())
+}
 
-    log::debug!(
-        "Mapping memory range {:#x} - {:#x}",
-        start_address.0,
-        end_address.0
-    );
+/// Map the grant table frames again after resuming, at the same address as before suspending
+///
+/// The page tables covering the grant table already exist so each frame is remapped directly.
+pub fn remap<const NUM_GRANT_FRAMES: usize>(table: *mut grant_entry_t) -> Result<(), Error> {
+    let mut frames = [0u64; NUM_GRANT_FRAMES];
 
-    for address in (start_address.0..end_address.0)
-        .step_by(PAGE_SIZE)
-        .map(|a| VirtualAddress(a))
-    {
-        log::trace!("starting loop");
-        // lookup L3 page entry
-        let l3_page = {
-            let l4_table = pt_base;
-            let pt_mfn =
-                MachineFrameNumber::from(VirtualAddress(pt_base as *const PageEntry as usize));
-            let offset = address.l4_table_offset();
-            let page = l4_table.offset(offset);
-
-            // if not present, map new L3 page table frame
-            if (*page).0 & PAGE_PRESENT == 0 {
-                let npf_pfn = MachineFrameNumber::from(VirtualAddress(alloc(PAGE_LAYOUT) as usize));
-                log::trace!("npf_pfn3: {}", npf_pfn.0);
-                new_frame(pt_base, npf_pfn.into(), pt_mfn, offset, 3);
-            }
-
-            *page
-        };
-
-        // lookup L2 page entry
-        let l2_page = {
-            let pt_mfn = MachineFrameNumber::from(l3_page);
-            let l3_table = VirtualAddress::from(PhysicalAddress(
-                PageFrameNumber::from(pt_mfn).0 << PAGE_SHIFT,
-            ))
-            .0 as *mut PageEntry;
-            let offset = address.l3_table_offset();
-            let page = l3_table.offset(offset);
-
-            // if not present, map new L2 page table frame
-            if (*page).0 & PAGE_PRESENT == 0 {
-                let npf_pfn = MachineFrameNumber::from(VirtualAddress(alloc(PAGE_LAYOUT) as usize));
-                log::trace!("npf_pfn2: {}", npf_pfn.0);
-                new_frame(pt_base, npf_pfn.into(), pt_mfn, offset, 2);
-            }
-
-            *page
-        };
-
-        // lookup L1 page entry
-        let l1_page = {
-            let pt_mfn = MachineFrameNumber::from(l2_page);
-            let l2_table = VirtualAddress::from(PhysicalAddress(
-                PageFrameNumber::from(pt_mfn).0 << PAGE_SHIFT,
-            ))
-            .0 as *mut PageEntry;
-            let offset = address.l2_table_offset();
-
-            let page = l2_table.offset(offset);
-
-            // if not present, map new L1 page table frame
-            if (*page).0 & PAGE_PRESENT == 0 {
-                let npf_pfn = MachineFrameNumber::from(VirtualAddress(alloc(PAGE_LAYOUT) as usize));
-                log::trace!("npf_pfn1: {}", npf_pfn.0);
-                new_frame(pt_base, npf_pfn.into(), pt_mfn, offset, 1);
-            }
-
-            *page
-        };
-
-        // lookup page, adding to current batch of mmu_updates if not present
-        {
-            let mfn_to_map = frames[(pfn_counter)];
-            pfn_counter += 1;
-
-            let pt_mfn = MachineFrameNumber::from(l1_page);
-            let l1_table = VirtualAddress::from(PhysicalAddress(
-                PageFrameNumber::from(pt_mfn).0 << PAGE_SHIFT,
-            ))
-            .0 as *mut PageEntry;
-            let offset = address.l1_table_offset();
-
-            if ((*l1_table.offset(offset)).0 & PAGE_PRESENT) == 0 {
-                mmu_updates[mmu_updates_index].ptr =
-                    ((pt_mfn.0 << PAGE_SHIFT) + size_of::<PageEntry>() * offset as usize) as u64;
-                mmu_updates[mmu_updates_index].val =
-                    ((mfn_to_map as usize) << PAGE_SHIFT | L1_PROT) as u64;
-                mmu_updates_index += 1;
-            }
-        }
-
-        // if number of mmu_updates is equal to the number of L1 page table entries
-        if mmu_updates_index == L1_PAGETABLE_ENTRIES
-        // OR we have reached the maximum page frame number
-            || address.0 + PAGE_SIZE == end_address.0
-        // issue MMU update hypercall
-        {
-            log::trace!("issuing update");
-            hypervisor_mmu_update(&mmu_updates[..mmu_updates_index])
-                .expect("PTE could not be updated");
-
-            mmu_updates_index = 0;
-        }
-
-        log::trace!("ending loop");
+    setup_table(DOMID_SELF, &mut frames)?;
+
+    let mut batch = Multicall::new();
+
+    for (i, frame) in frames.iter().enumerate() {
+        batch.update_va_mapping(
+            VirtualAddress(table as usize + i * PAGE_SIZE),
+            PageEntry((*frame as usize) << PAGE_SHIFT | L1_PROT_NX),
+            TLBFlushFlags::INVLPG,
+        );
     }
+
+    for result in batch.submit()? {
+        result?;
+    }
+
+    Ok(())
 }
@@ -1,6 +1,8 @@
 //! x86_64 platform code
 
+pub mod backtrace;
 pub mod consts;
+pub mod fpu;
 pub mod grant_table;
 pub mod hypercall;
 pub mod time;
@@ -1,6 +1,9 @@
 //! x86_64 Xen Time
 
-use {crate::SHARED_INFO, core::arch::x86_64::_rdtsc};
+use {
+    crate::{vcpu, SHARED_INFO},
+    core::arch::x86_64::_rdtsc,
+};
 
 /// Gets the current system time represented as the number of nanoseconds since 1970-01-01 00:00:00 UTC
 ///
@@ -9,10 +12,42 @@ pub fn get_system_time() -> u64 {
     let shared_info = &unsafe { *SHARED_INFO };
 
     let mut wc_version;
-    let mut version;
-
     let mut seconds;
     let mut nanoseconds;
+
+    loop {
+        // if the lowest bit of the version is 1 then the time is being updated so spin until finished
+        loop {
+            wc_version = shared_info.wc_version;
+            if wc_version & 1 == 0 {
+                break;
+            }
+        }
+
+        seconds = shared_info.wc_sec;
+        nanoseconds = shared_info.wc_nsec;
+
+        // break only if all values were read from the same update version
+        if wc_version == shared_info.wc_version {
+            break;
+        }
+    }
+
+    get_monotonic_time() // time in nanoseconds since boot
+        + (u64::from(seconds) * 1_000_000_000) // current system time seconds
+        + u64::from(nanoseconds) // current system time nanoseconds
+}
+
+/// Gets the number of nanoseconds since the domain booted
+///
+/// Unlike `get_system_time` this is not affected by changes to the wall clock. Each vCPU has its own
+/// time info, as TSCs are not necessarily synchronised.
+pub fn get_monotonic_time() -> u64 {
+    // borrowed rather than copied as the hypervisor updates it concurrently
+    let time = unsafe { &(*vcpu::info()).time };
+
+    let mut version;
+
     let mut system_time;
     let mut old_tsc;
 
@@ -20,27 +55,22 @@ pub fn get_system_time() -> u64 {
     let mut mul;
 
     loop {
-        // if the lowest bit of either version is 1 then the time is being updated so spin until finished
+        // if the lowest bit of the version is 1 then the time is being updated so spin until finished
         loop {
-            wc_version = shared_info.wc_version;
-            version = shared_info.vcpu_info[0].time.version;
-            if !(version & 1 == 1 || wc_version & 1 == 1) {
+            version = time.version;
+            if version & 1 == 0 {
                 break;
             }
         }
 
-        seconds = shared_info.wc_sec;
-        nanoseconds = shared_info.wc_nsec;
-        system_time = shared_info.vcpu_info[0].time.system_time;
-        old_tsc = shared_info.vcpu_info[0].time.tsc_timestamp;
+        system_time = time.system_time;
+        old_tsc = time.tsc_timestamp;
 
-        shift = shared_info.vcpu_info[0].time.tsc_shift;
-        mul = shared_info.vcpu_info[0].time.tsc_to_system_mul;
+        shift = time.tsc_shift;
+        mul = time.tsc_to_system_mul;
 
         // break only if all values were read from the same update version
-        if !(version != shared_info.vcpu_info[0].time.version
-            || wc_version != shared_info.wc_version)
-        {
+        if version == time.version {
             break;
         }
     }
@@ -58,8 +88,5 @@ pub fn get_system_time() -> u64 {
         (delta * u64::from(mul)) >> 32
     };
 
-    system_time // time in nanoseconds since boot
-        + tsc_nanos // Time Stamp Counter nanoseconds
-        + (u64::from(seconds) * 1_000_000_000) // current system time seconds
-        + u64::from(nanoseconds) // current system time nanoseconds
+    system_time + tsc_nanos
 }
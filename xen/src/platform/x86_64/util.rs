@@ -1,6 +1,14 @@
 //! Utility functions
 
-use core::arch::asm;
+use {
+    crate::SHARED_INFO,
+    alloc::{
+        alloc::{alloc_zeroed, Layout},
+        boxed::Box,
+    },
+    core::{arch::asm, mem::size_of, ptr::addr_of_mut},
+    xen_sys::vcpu_info,
+};
 
 /// Synchronised bit set
 pub unsafe fn synch_set_bit(nr: u64, addr: *mut u64) {
@@ -12,17 +20,38 @@ pub unsafe fn synch_clear_bit(nr: u64, addr: *mut u64) {
     asm!("lock", "btr [{}], {}", in(reg) addr, in(reg) nr);
 }
 
-struct PDA {
+/// Per-CPU data area, addressed through the GS base
+///
+/// `bootstrap.S` relies on `irqcount`, `irqstackptr` and `vcpu_info` being at offsets 0, 8 and 24.
+#[repr(C)]
+struct Pda {
     irqcount: i32,
     irqstackptr: *mut u8,
+    id: usize,
+    vcpu_info: *mut vcpu_info,
 }
 
-static mut PDA: PDA = PDA {
+/// Per-CPU data area of the boot vCPU
+static mut PDA: Pda = Pda {
     irqcount: -1,
     irqstackptr: core::ptr::null_mut(),
+    id: 0,
+    vcpu_info: core::ptr::null_mut(),
 };
 
-static mut IRQSTACK: [u8; 2 * 32768] = [0; 2 * 32768];
+/// Stack used while handling events, the first page is left as a guard page
+#[repr(C, align(32768))]
+struct IrqStack([u8; 2 * 32768]);
+
+static mut IRQSTACK: IrqStack = IrqStack([0; 2 * 32768]);
+
+/// Model specific register holding the GS base
+const MSR_GS_BASE: u32 = 0xc0000101;
+
+/// Returns the address of the guard page at the bottom of the event stack
+pub fn irq_stack_guard() -> usize {
+    unsafe { IRQSTACK.0.as_ptr() as usize }
+}
 
 unsafe fn write_msr(msr: u32, value: u64) {
     let low = value as u32;
@@ -36,13 +65,44 @@ unsafe fn write_msr(msr: u32, value: u64) {
     );
 }
 
-/// Initialise events
-pub fn init_events() {
+/// Initialise the per-CPU data area of the boot vCPU
+pub fn init_pda() {
     unsafe {
-        // asm!("movl 0, %fs", options(nostack, preserves_flags));
-        // asm!("movl 0, %gs", options(nostack, preserves_flags));
-        write_msr(0xc0000101, &mut PDA as *mut _ as u64);
         PDA.irqcount = -1;
-        PDA.irqstackptr = (((IRQSTACK.as_mut_ptr() as u64) + 2 * 32768) & !(32768 - 1)) as *mut _;
+        PDA.irqstackptr = IRQSTACK.0.as_mut_ptr().add(2 * 32768);
+        PDA.vcpu_info = addr_of_mut!((*SHARED_INFO).vcpu_info[0]);
+        write_msr(MSR_GS_BASE, &mut PDA as *mut _ as u64);
+    }
+}
+
+/// Allocates the per-CPU data area and event stack of vCPU `id`, returning the address to use as its GS base
+/// and that of the guard page at the bottom of the event stack
+pub fn new_pda(id: usize) -> (u64, usize) {
+    let stack = unsafe { alloc_zeroed(Layout::new::<IrqStack>()) };
+    if stack.is_null() {
+        panic!("Failed to allocate event stack");
     }
+
+    let pda = Box::leak(Box::new(Pda {
+        irqcount: -1,
+        irqstackptr: unsafe { stack.add(size_of::<IrqStack>()) },
+        id,
+        vcpu_info: unsafe { addr_of_mut!((*SHARED_INFO).vcpu_info[id]) },
+    }));
+
+    (pda as *mut Pda as u64, stack as usize)
+}
+
+/// Gets the ID of the vCPU executing the caller
+pub fn cpu_id() -> usize {
+    let id;
+    unsafe { asm!("mov {}, gs:[16]", out(reg) id, options(nostack, readonly, preserves_flags)) };
+    id
+}
+
+/// Gets the shared info page entry of the vCPU executing the caller
+pub fn cpu_info() -> *mut vcpu_info {
+    let info;
+    unsafe { asm!("mov {}, gs:[24]", out(reg) info, options(nostack, readonly, preserves_flags)) };
+    info
 }
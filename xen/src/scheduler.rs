@@ -1,7 +1,8 @@
 //! Virtual machine scheduler interface
 
 use {
-    crate::hypercall,
+    crate::{hypercall, memory::MachineFrameNumber},
+    core::convert::TryInto,
     xen_sys::{
         evtchn_port_t, sched_pin_override_t, sched_poll_t, sched_remote_shutdown_t,
         sched_shutdown_t, sched_watchdog_t, SCHEDOP_block, SCHEDOP_pin_override, SCHEDOP_poll,
@@ -100,6 +101,76 @@ unsafe fn sched_op(cmd: u32, arg: u64) {
     hypercall!(__HYPERVISOR_sched_op, cmd, arg).expect("Failed schedule operation");
 }
 
+/// Suspend the domain, supplying the MFN of the start info page as required for PV guests
+///
+/// Returns `true` if the suspend was cancelled or the domain was checkpointed, and `false` if resuming in a new domain.
+pub fn suspend(start_info: MachineFrameNumber) -> Result<bool, hypercall::Error> {
+    let arg = sched_shutdown_t {
+        reason: ShutdownReason::Suspend as u32,
+    };
+
+    unsafe {
+        hypercall!(
+            __HYPERVISOR_sched_op,
+            SCHEDOP_shutdown,
+            &arg as *const sched_shutdown_t as u64,
+            start_info.0 as u64
+        )
+    }
+    .map(|cancelled| cancelled != 0)
+}
+
+/// Domain watchdog timer
+///
+/// Xen shuts the domain down with `ShutdownReason::Watchdog` if the timer is not poked before the timeout expires. Disarmed when dropped.
+#[derive(Debug)]
+pub struct Watchdog {
+    id: u32,
+    timeout: u32,
+}
+
+impl Watchdog {
+    /// Arm a new watchdog timer with a timeout in seconds
+    pub fn new(timeout: u32) -> Result<Self, hypercall::Error> {
+        let id = watchdog_op(0, timeout)?
+            .try_into()
+            .expect("Failed to convert u64 to u32");
+
+        Ok(Self { id, timeout })
+    }
+
+    /// Reset the timer to the full timeout
+    pub fn poke(&self) -> Result<(), hypercall::Error> {
+        watchdog_op(self.id, self.timeout).map(|_| ())
+    }
+
+    /// Timeout in seconds
+    pub fn timeout(&self) -> u32 {
+        self.timeout
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Err(e) = watchdog_op(self.id, 0) {
+            log::warn!("Failed to disarm watchdog {}: {}", self.id, e);
+        }
+    }
+}
+
+/// Set up, poke or destroy a watchdog timer, returning the ID of a newly created timer
+fn watchdog_op(id: u32, timeout: u32) -> Result<u64, hypercall::Error> {
+    let arg = sched_watchdog_t { id, timeout };
+
+    unsafe {
+        hypercall!(
+            __HYPERVISOR_sched_op,
+            SCHEDOP_watchdog,
+            &arg as *const sched_watchdog_t as u64
+        )
+    }
+}
+
 ///
 pub fn schedule_operation(cmd: Command) {
     unsafe {
@@ -49,3 +49,13 @@ pub fn end() -> usize {
 
     unsafe { &_end as *const u8 as usize }
 }
+
+/// Returns the address of the guard page below the boot stack
+#[inline]
+pub fn stack_guard() -> usize {
+    extern "C" {
+        static mut __STACK_GUARD: u8;
+    }
+
+    unsafe { &__STACK_GUARD as *const u8 as usize }
+}
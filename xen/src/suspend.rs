@@ -0,0 +1,143 @@
+//! Suspend and resume
+//!
+//! Implements the PV guest side of save/restore and live migration. When resuming in a new domain
+//! the start info page describes the new domain, all event channels and grants have been lost and
+//! every machine frame may have changed.
+
+use {
+    crate::{
+        console, events, grant_table, hypercall, map_shared_info,
+        memory::{
+            self, update_va_mapping, MachineFrameNumber, PageEntry, PageFrameNumber, TLBFlushFlags,
+            VirtualAddress,
+        },
+        scheduler, vcpu, xenbus, xenstore, SHARED_INFO, START_INFO,
+    },
+    core::{
+        convert::TryInto,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    log::info,
+};
+
+/// Outcome of a suspend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// The suspend was cancelled or the domain was checkpointed, execution continues in the original domain
+    Cancelled,
+    /// Execution continues in a new domain, possibly on another host
+    NewDomain,
+}
+
+/// Number of times the domain has suspended
+static SUSPENDS: AtomicUsize = AtomicUsize::new(0);
+
+/// Publish the location of the MFN list so that the domain can be saved
+///
+/// Requires that the allocator be initialised before calling.
+pub fn init() {
+    memory::build_p2m_frame_list(nr_pages());
+}
+
+/// Suspend the domain, returning once it has resumed
+///
+/// Must be called on vCPU 0 and not from an event handler, with all other vCPUs taken down with
+/// `vcpu::down` as they would run while the shared info page is unmapped. They are brought up again
+/// with `vcpu::up`, or `vcpu::start` after resuming in a new domain, where the caller must also register
+/// XenBus watches again with `xenbus::resume` and reconnect any frontend devices.
+///
+/// If the hypervisor fails the suspend, execution continues in the original domain as if it were cancelled
+/// and the error is returned.
+pub fn suspend() -> Result<Resume, hypercall::Error> {
+    info!("suspending");
+
+    SUSPENDS.fetch_add(1, Ordering::Relaxed);
+
+    // SAFETY: no events are delivered while the shared info page is unmapped
+    unsafe { (*vcpu::info()).evtchn_upcall_mask = 1 };
+
+    grant_table::suspend();
+
+    // the toolstack expects the frames of the XenStore and console rings as PFNs so they can be translated for the new domain
+    canonicalize(|mfn| PageFrameNumber::from(MachineFrameNumber(mfn)).0);
+
+    let start_info_mfn = MachineFrameNumber::from(VirtualAddress(unsafe { START_INFO } as usize));
+
+    update_va_mapping(
+        VirtualAddress(unsafe { SHARED_INFO } as usize),
+        PageEntry(0),
+        TLBFlushFlags::INVLPG,
+    )
+    .expect("Failed to unmap shared info page");
+
+    let result = scheduler::suspend(start_info_mfn);
+
+    let resume = if matches!(result, Ok(false)) {
+        // the start info page has been rewritten by the toolstack to describe the new domain
+        Resume::NewDomain
+    } else {
+        // the start info page is unchanged so the ring frames must be translated back
+        canonicalize(|pfn| MachineFrameNumber::from(PageFrameNumber(pfn)).0);
+        Resume::Cancelled
+    };
+
+    memory::init_mfn_list(
+        unsafe { *START_INFO }
+            .mfn_list
+            .try_into()
+            .expect("Failed to convert u64 to usize"),
+    );
+    map_shared_info();
+    memory::build_p2m_frame_list(nr_pages());
+
+    grant_table::resume(resume == Resume::NewDomain);
+
+    if resume == Resume::NewDomain {
+        events::resume();
+        console::Writer::resume(unsafe { &*START_INFO });
+        xenstore::resume();
+        xenbus::update_event_channel();
+    }
+
+    unsafe { (*vcpu::info()).evtchn_upcall_mask = 0 };
+
+    result?;
+
+    info!("resumed: {:?}", resume);
+
+    Ok(resume)
+}
+
+/// Gets the number of times the domain has suspended, including suspends that were cancelled
+///
+/// Monotonic time may jump while suspended, so callers timing an operation compare this before and after.
+pub fn count() -> usize {
+    SUSPENDS.load(Ordering::Relaxed)
+}
+
+/// Apply `f` to the frame numbers of the XenStore and console rings in the start info page
+fn canonicalize<F: Fn(usize) -> usize>(f: F) {
+    // SAFETY: START_INFO is valid and the OS is running in an unprivileged (non-Dom0) domain
+    unsafe {
+        let start_info = &mut *START_INFO;
+
+        start_info.store_mfn = f(start_info
+            .store_mfn
+            .try_into()
+            .expect("Failed to convert u64 to usize")) as u64;
+
+        start_info.console.domU.mfn = f(start_info
+            .console
+            .domU
+            .mfn
+            .try_into()
+            .expect("Failed to convert u64 to usize")) as u64;
+    }
+}
+
+fn nr_pages() -> usize {
+    unsafe { *START_INFO }
+        .nr_pages
+        .try_into()
+        .expect("Failed to convert u64 to usize")
+}
@@ -0,0 +1,92 @@
+//! Synchronisation primitives
+
+use {
+    crate::events::{mask_upcalls, restore_upcalls},
+    core::{
+        marker::PhantomData,
+        mem::ManuallyDrop,
+        ops::{Deref, DerefMut},
+    },
+    spin::{Mutex, MutexGuard},
+};
+
+/// Spinlock that masks event delivery to the current vCPU while held
+///
+/// Data shared with event handlers must be protected by an `IrqSafeMutex` rather than a `spin::Mutex`,
+/// otherwise a handler interrupting the lock holder on the same vCPU spins forever.
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+}
+
+impl<T> IrqSafeMutex<T> {
+    /// Creates a new unlocked mutex containing `value`
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Masks events then spins until the lock is acquired
+    pub fn lock(&self) -> IrqSafeMutexGuard<T> {
+        let mask = mask_upcalls();
+
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            mask,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire the lock without spinning, returning `None` if it is held
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<T>> {
+        let mask = mask_upcalls();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                mask,
+                _not_send: PhantomData,
+            }),
+            None => {
+                restore_upcalls(mask);
+                None
+            }
+        }
+    }
+
+    /// Whether the lock is currently held
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+/// Guard of a locked `IrqSafeMutex`, restoring the previous event mask once the lock is released
+///
+/// Not `Send`, as the mask belongs to the vCPU that acquired the lock.
+pub struct IrqSafeMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    mask: u8,
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: the guard is not used again, and the lock is released before events are unmasked
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        restore_upcalls(self.mask);
+    }
+}
@@ -11,7 +11,7 @@ use {
     xen_sys::{__HYPERVISOR_set_timer_op, VIRQ_TIMER},
 };
 
-pub use crate::platform::time::get_system_time;
+pub use crate::platform::time::{get_monotonic_time, get_system_time};
 
 /// Initialise time
 pub fn init() {
@@ -1,6 +1,11 @@
 //! Trap handling
 
-use {crate::hypercall, xen_sys::__HYPERVISOR_set_trap_table};
+use {
+    crate::{hypercall, vcpu},
+    bitflags::bitflags,
+    core::fmt,
+    xen_sys::__HYPERVISOR_set_trap_table,
+};
 
 /// Information for trap handler
 #[repr(C)]
@@ -23,3 +28,92 @@ pub fn set_trap_table(table: &'static [TrapInfo]) {
     unsafe { hypercall!(__HYPERVISOR_set_trap_table, table.as_ptr() as u64) }
         .expect("Failed to set trap table");
 }
+
+/// Registers saved by the exception entry stubs in `bootstrap.S`, passed to each trap handler
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(missing_docs)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rax: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    /// Error code slot, overwritten with -1 once the error code has been passed to the handler
+    pub orig_rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP: {:04x}:{:016x} RFLAGS: {:016x}",
+            self.cs, self.rip, self.rflags
+        )?;
+        writeln!(f, "RSP: {:04x}:{:016x}", self.ss, self.rsp)?;
+        writeln!(
+            f,
+            "RAX: {:016x} RBX: {:016x} RCX: {:016x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX: {:016x} RSI: {:016x} RDI: {:016x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP: {:016x} R8:  {:016x} R9:  {:016x}",
+            self.rbp, self.r8, self.r9
+        )?;
+        writeln!(
+            f,
+            "R10: {:016x} R11: {:016x} R12: {:016x}",
+            self.r10, self.r11, self.r12
+        )?;
+        write!(
+            f,
+            "R13: {:016x} R14: {:016x} R15: {:016x}",
+            self.r13, self.r14, self.r15
+        )
+    }
+}
+
+bitflags! {
+/// Page fault error code
+pub struct PageFaultError: u64 {
+    /// Fault caused by a protection violation rather than a non-present page
+    const PRESENT = 1 << 0;
+    /// Fault caused by a write rather than a read
+    const WRITE = 1 << 1;
+    /// Fault occurred in user mode
+    const USER = 1 << 2;
+    /// Reserved bit set in a page table entry
+    const RESERVED = 1 << 3;
+    /// Fault caused by an instruction fetch
+    const INSTRUCTION = 1 << 4;
+    /// Fault caused by a protection key violation
+    const PROTECTION_KEY = 1 << 5;
+}}
+
+/// Gets the address of the most recent page fault
+///
+/// Xen supplies the faulting address in the vCPU info rather than CR2.
+pub fn fault_address() -> usize {
+    unsafe { (*vcpu::info()).arch.cr2 as usize }
+}
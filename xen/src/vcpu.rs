@@ -0,0 +1,292 @@
+//! Virtual CPUs
+//!
+//! vCPU 0 is started by the hypervisor, the others are brought up with `VCPUOP_initialise` and
+//! `VCPUOP_up`. Each has its own stack and per-CPU data area, addressed through the GS base, holding
+//! its event stack and ID, which are reused if the vCPU is started again after resuming in a new domain. Each vCPU must register its own trap table and callbacks, and enable event
+//! delivery with `events::init_cpu`, as these are per-vCPU state in the hypervisor.
+
+use {
+    crate::{
+        hypercall,
+        memory::{MachineFrameNumber, VirtualAddress},
+        platform::{
+            consts::{PAGE_SHIFT, PAGE_SIZE},
+            util::{cpu_id, cpu_info, new_pda},
+        },
+        xenstore, START_INFO,
+    },
+    alloc::{
+        alloc::{alloc_zeroed, Layout},
+        format,
+        vec::Vec,
+    },
+    core::mem,
+    displaydoc::Display,
+    spin::Mutex,
+    xen_sys::{
+        __HYPERVISOR_vcpu_op, vcpu_guest_context, vcpu_info, vcpu_register_vcpu_info_t,
+        vcpu_runstate_info_t, vcpu_set_periodic_timer_t, RUNSTATE_blocked, RUNSTATE_offline,
+        RUNSTATE_runnable, RUNSTATE_running, VCPUOP_down, VCPUOP_get_runstate_info,
+        VCPUOP_initialise, VCPUOP_is_up, VCPUOP_register_vcpu_info, VCPUOP_set_periodic_timer,
+        VCPUOP_stop_periodic_timer, VCPUOP_up, FLAT_KERNEL_CS, FLAT_KERNEL_DS, FLAT_KERNEL_SS,
+        XEN_LEGACY_MAX_VCPUS,
+    },
+};
+
+/// Maximum number of vCPUs, limited by the `vcpu_info` array in the shared info page
+pub const MAX_VCPUS: usize = XEN_LEGACY_MAX_VCPUS as usize;
+
+/// Size of the stack allocated to each secondary vCPU, the first page is left as a guard page
+const STACK_SIZE: usize = 64 * 1024;
+
+/// Stacks of each secondary vCPU that has been started
+static STACKS: Mutex<[Option<Stacks>; MAX_VCPUS]> = Mutex::new([None; MAX_VCPUS]);
+
+/// `VGCF_in_kernel`, the vCPU starts in kernel mode
+const VGCF_IN_KERNEL: u64 = 1 << 2;
+
+/// Initial flags, I/O privilege level 1 as used by the hypervisor for PV kernels
+const INITIAL_RFLAGS: u64 = 0x1000;
+
+extern "C" {
+    fn hypervisor_callback();
+    fn failsafe_callback();
+}
+
+/// Stack and per-CPU data area allocated to a secondary vCPU
+#[derive(Debug, Clone, Copy)]
+struct Stacks {
+    /// Top of the stack
+    top: u64,
+    /// Guard page at the bottom of the stack
+    guard: VirtualAddress,
+    /// Address of the per-CPU data area
+    gs_base: u64,
+    /// Guard page at the bottom of the event stack
+    event_guard: VirtualAddress,
+}
+
+/// Scheduling state of a vCPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runstate {
+    /// Running on a physical CPU
+    Running,
+    /// Ready to run but waiting for a physical CPU
+    Runnable,
+    /// Blocked waiting for an event
+    Blocked,
+    /// Not runnable, either down or paused by the toolstack
+    Offline,
+}
+
+/// Scheduling state of a vCPU and the time it has spent in each state
+#[derive(Debug, Clone, Copy)]
+pub struct RunstateInfo {
+    /// Current state
+    pub state: Runstate,
+    /// System time in nanoseconds when the current state was entered
+    pub state_entry_time: u64,
+    /// Nanoseconds spent in each state, indexed by `RUNSTATE_*`
+    pub time: [u64; 4],
+}
+
+/// Error getting the scheduling state of a vCPU
+#[derive(Debug, Display)]
+pub enum RunstateError {
+    /// Hypercall failed getting runstate: {0}
+    Hypercall(hypercall::Error),
+    /// Unknown runstate {0}
+    Unknown(u32),
+}
+
+impl From<hypercall::Error> for RunstateError {
+    fn from(e: hypercall::Error) -> Self {
+        Self::Hypercall(e)
+    }
+}
+
+/// vCPU operation hypercall
+unsafe fn vcpu_op(cmd: u32, id: usize, arg: u64) -> Result<u64, hypercall::Error> {
+    hypercall!(__HYPERVISOR_vcpu_op, cmd, id as u64, arg)
+}
+
+/// Gets the ID of the vCPU executing the caller
+pub fn id() -> usize {
+    cpu_id()
+}
+
+/// Gets the shared info page entry of the vCPU executing the caller
+pub fn info() -> *mut vcpu_info {
+    cpu_info()
+}
+
+/// Gets the IDs of the vCPUs online in XenStore in ascending order
+///
+/// IDs need not be contiguous, as the toolstack may take any vCPU offline.
+pub fn online() -> Vec<usize> {
+    let mut ids = xenstore::ls("cpu\0")
+        .expect("Failed to list XenStore vCPUs")
+        .iter()
+        .filter_map(|cpu| cpu.parse::<usize>().ok())
+        .filter(|cpu| *cpu < MAX_VCPUS)
+        .filter(|cpu| {
+            xenstore::read(format!("cpu/{}/availability\0", cpu))
+                .expect("Failed to read vCPU availability")
+                == "online"
+        })
+        .collect::<Vec<_>>();
+
+    ids.sort_unstable();
+    ids
+}
+
+/// Starts vCPU `id` running `entry` with its ID as the argument
+///
+/// The stack is allocated the first time vCPU `id` is started. After resuming in a new domain, secondary
+/// vCPUs are no longer initialised and must be started again.
+pub fn start(id: usize, entry: extern "C" fn(usize) -> !) -> Result<(), hypercall::Error> {
+    assert!(id > 0 && id < MAX_VCPUS, "Invalid vCPU ID {}", id);
+
+    let stacks = *STACKS.lock()[id].get_or_insert_with(|| {
+        let stack = unsafe {
+            alloc_zeroed(
+                Layout::from_size_align(STACK_SIZE, PAGE_SIZE)
+                    .expect("Failed to create stack layout"),
+            )
+        };
+        if stack.is_null() {
+            panic!("Failed to allocate stack for vCPU {}", id);
+        }
+
+        let (gs_base, event_guard) = new_pda(id);
+
+        Stacks {
+            top: stack as u64 + STACK_SIZE as u64,
+            guard: VirtualAddress(stack as usize),
+            gs_base,
+            event_guard: VirtualAddress(event_guard),
+        }
+    });
+    let stack_top = stacks.top;
+
+    let pt_base = MachineFrameNumber::from(VirtualAddress(unsafe { *START_INFO }.pt_base as usize));
+
+    // SAFETY: all-zero is a valid context, every field is an integer
+    let mut context: vcpu_guest_context = unsafe { mem::zeroed() };
+
+    context.flags = VGCF_IN_KERNEL;
+
+    // register names are members of anonymous unions in the generated bindings
+    let regs = &mut context.user_regs;
+    regs.__bindgen_anon_7.rdi = id as u64;
+    regs.__bindgen_anon_8.rip = entry as u64;
+    regs.cs = FLAT_KERNEL_CS as u16;
+    regs.__bindgen_anon_9.rflags = INITIAL_RFLAGS;
+    // as if `entry` had been called, leaving the stack misaligned by the return address
+    regs.__bindgen_anon_10.rsp = stack_top - 8;
+    regs.ss = FLAT_KERNEL_SS as u16;
+    regs.ds = FLAT_KERNEL_DS as u16;
+    regs.es = FLAT_KERNEL_DS as u16;
+
+    context.kernel_ss = FLAT_KERNEL_SS.into();
+    context.kernel_sp = stack_top;
+    context.ctrlreg[3] = (pt_base.0 << PAGE_SHIFT) as u64;
+    context.event_callback_eip = hypervisor_callback as u64;
+    context.failsafe_callback_eip = failsafe_callback as u64;
+    context.gs_base_kernel = stacks.gs_base;
+
+    unsafe {
+        vcpu_op(VCPUOP_initialise, id, &context as *const _ as u64)?;
+        vcpu_op(VCPUOP_up, id, 0)?;
+    }
+
+    Ok(())
+}
+
+/// Gets the guard pages at the bottom of the stack and event stack of vCPU `id`, once it has been started
+///
+/// The guard pages are left mapped for the caller to unmap.
+pub fn stack_guards(id: usize) -> Option<(VirtualAddress, VirtualAddress)> {
+    STACKS.lock()[id].map(|stacks| (stacks.guard, stacks.event_guard))
+}
+
+/// Takes vCPU `id` down, it stops running until brought up again with `up`
+///
+/// A vCPU taken down is not saved when suspending.
+pub fn down(id: usize) -> Result<(), hypercall::Error> {
+    unsafe { vcpu_op(VCPUOP_down, id, 0) }?;
+
+    Ok(())
+}
+
+/// Brings vCPU `id` up again after `down`, continuing from where it was taken down
+pub fn up(id: usize) -> Result<(), hypercall::Error> {
+    unsafe { vcpu_op(VCPUOP_up, id, 0) }?;
+
+    Ok(())
+}
+
+/// Whether vCPU `id` is running, rather than down
+pub fn is_up(id: usize) -> Result<bool, hypercall::Error> {
+    Ok(unsafe { vcpu_op(VCPUOP_is_up, id, 0) }? != 0)
+}
+
+/// Gets the scheduling state of vCPU `id`
+pub fn runstate(id: usize) -> Result<RunstateInfo, RunstateError> {
+    // SAFETY: all-zero is a valid runstate info, every field is an integer
+    let mut info: vcpu_runstate_info_t = unsafe { mem::zeroed() };
+
+    unsafe { vcpu_op(VCPUOP_get_runstate_info, id, &mut info as *mut _ as u64) }?;
+
+    let state = match info.state as u32 {
+        RUNSTATE_running => Runstate::Running,
+        RUNSTATE_runnable => Runstate::Runnable,
+        RUNSTATE_blocked => Runstate::Blocked,
+        RUNSTATE_offline => Runstate::Offline,
+        state => return Err(RunstateError::Unknown(state)),
+    };
+
+    Ok(RunstateInfo {
+        state,
+        state_entry_time: info.state_entry_time,
+        time: info.time,
+    })
+}
+
+/// Moves the `vcpu_info` of vCPU `id` out of the shared info page to `offset` bytes into machine frame `mfn`
+///
+/// # Safety
+///
+/// The frame must remain mapped and unused for the lifetime of the domain, and the entry in the shared
+/// info page returned by `info` is no longer updated by the hypervisor.
+pub unsafe fn register_vcpu_info(
+    id: usize,
+    mfn: MachineFrameNumber,
+    offset: usize,
+) -> Result<(), hypercall::Error> {
+    let mut info = vcpu_register_vcpu_info_t {
+        mfn: mfn.0 as u64,
+        offset: offset as u32,
+        rsvd: 0,
+    };
+
+    vcpu_op(VCPUOP_register_vcpu_info, id, &mut info as *mut _ as u64)?;
+
+    Ok(())
+}
+
+/// Delivers `VIRQ_TIMER` to vCPU `id` every `period` nanoseconds, in addition to the single shot timer
+pub fn set_periodic_timer(id: usize, period: u64) -> Result<(), hypercall::Error> {
+    let mut timer = vcpu_set_periodic_timer_t { period_ns: period };
+
+    unsafe { vcpu_op(VCPUOP_set_periodic_timer, id, &mut timer as *mut _ as u64) }?;
+
+    Ok(())
+}
+
+/// Stops the periodic timer of vCPU `id`
+pub fn stop_periodic_timer(id: usize) -> Result<(), hypercall::Error> {
+    unsafe { vcpu_op(VCPUOP_stop_periodic_timer, id, 0) }?;
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Hypervisor version and capabilities
+
+use {
+    crate::hypercall,
+    alloc::string::String,
+    core::fmt,
+    xen_sys::{
+        __HYPERVISOR_xen_version, xen_capabilities_info_t, xen_extraversion_t, xen_feature_info_t,
+        XENVER_capabilities, XENVER_extraversion, XENVER_get_features, XENVER_version,
+    },
+};
+
+/// Version of the hypervisor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    /// Major version number
+    pub major: u16,
+    /// Minor version number
+    pub minor: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Version operation hypercall
+unsafe fn xen_version(cmd: u32, arg: u64) -> Result<u64, hypercall::Error> {
+    hypercall!(__HYPERVISOR_xen_version, cmd, arg)
+}
+
+/// Gets the major and minor version of the hypervisor
+///
+/// Has no side effects and does not allocate, so is also used to enter the hypervisor to deliver pending events.
+pub fn version() -> Version {
+    let version = unsafe { xen_version(XENVER_version, 0) }
+        .expect("xen_version version operation can never fail");
+
+    Version {
+        major: (version >> 16) as u16,
+        minor: version as u16,
+    }
+}
+
+/// Gets the extra version string of the hypervisor, such as `.0-rc1`
+pub fn extraversion() -> Result<String, hypercall::Error> {
+    let mut extraversion: xen_extraversion_t = [0; 16];
+
+    unsafe { xen_version(XENVER_extraversion, extraversion.as_mut_ptr() as u64) }?;
+
+    Ok(c_string(&extraversion))
+}
+
+/// Gets the space separated list of guest ABIs supported by the hypervisor, such as `xen-3.0-x86_64`
+pub fn capabilities() -> Result<String, hypercall::Error> {
+    let mut capabilities: xen_capabilities_info_t = [0; 1024];
+
+    unsafe { xen_version(XENVER_capabilities, capabilities.as_mut_ptr() as u64) }?;
+
+    Ok(c_string(&capabilities))
+}
+
+/// Gets the feature flags in submap `index`, each of which describes 32 `XENFEAT_*` features
+pub fn features(index: u32) -> Result<u32, hypercall::Error> {
+    let mut info = xen_feature_info_t {
+        submap_idx: index,
+        submap: 0,
+    };
+
+    unsafe { xen_version(XENVER_get_features, &mut info as *mut _ as u64) }?;
+
+    Ok(info.submap)
+}
+
+/// Converts a null terminated string returned by the hypervisor
+fn c_string(chars: &[cty::c_char]) -> String {
+    chars
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8 as char)
+        .collect()
+}
@@ -6,19 +6,30 @@ use {
     crate::{
         events::event_channel_op,
         memory::{MachineFrameNumber, VirtualAddress},
+        scheduler::{schedule_operation, Command},
+        time::get_monotonic_time,
         START_INFO,
     },
-    alloc::{collections::BTreeMap, string::String},
+    alloc::{
+        borrow::ToOwned,
+        collections::{BTreeMap, VecDeque},
+        format,
+        string::String,
+        vec::Vec,
+    },
     core::{
         cmp,
         convert::TryInto,
+        future::Future,
         mem::size_of,
+        pin::Pin,
         ptr::copy_nonoverlapping,
-        slice,
+        slice, str,
         sync::atomic::{fence, Ordering},
+        task::{Context, Poll},
     },
     lazy_static::lazy_static,
-    log::debug,
+    log::{debug, warn},
     spin::Mutex,
     xen_sys::{
         evtchn_send_t, xenbus_state_XenbusStateClosed, xenbus_state_XenbusStateClosing,
@@ -57,10 +68,11 @@ lazy_static! {
         let event_channel = unsafe { *START_INFO }.store_evtchn;
 
         let responses = BTreeMap::new();
+        let watches = BTreeMap::new();
 
         debug!("Initialized XenBus: {:p}", interface);
 
-        Mutex::new(XenBus { interface, event_channel, responses})
+        Mutex::new(XenBus { interface, event_channel, responses, watches, in_flight: 0 })
     };
 }
 
@@ -79,10 +91,10 @@ pub async fn request(kind: MessageKind, data: &[&[u8]], tx_id: u32) -> (MessageH
 
     XENBUS.lock().write(header, data);
 
-    // call "background" task directly to block until response is received
-    task::task();
-
     loop {
+        // call "background" task directly to block until a message is received, which may be a watch event rather than the response
+        task::task();
+
         // assign result of removal to limit lifetime of held lock
         let resp = XENBUS.lock().responses.remove(&0);
         if let Some(r) = resp {
@@ -91,11 +103,138 @@ pub async fn request(kind: MessageKind, data: &[&[u8]], tx_id: u32) -> (MessageH
     }
 }
 
+/// Registers a watch on a path, events for which are received from `watch_event` with the same token
+///
+/// Both `path` and `token` must be nul-terminated. XenStore fires the watch once immediately after it is registered.
+pub async fn watch(path: &str, token: &str) {
+    // create the queue first so the initial event is not discarded, a token registered again watches the new path
+    XENBUS
+        .lock()
+        .watches
+        .entry(token.trim_end_matches('\0').to_owned())
+        .and_modify(|watch| watch.path = path.to_owned())
+        .or_insert_with(|| Watch {
+            path: path.to_owned(),
+            events: VecDeque::new(),
+        });
+
+    request(MessageKind::Watch, &[path.as_bytes(), token.as_bytes()], 0).await;
+}
+
+/// Registers all watches again after resuming in a new domain, as they are not preserved by the new XenStore
+pub async fn resume() {
+    let watches = XENBUS
+        .lock()
+        .watches
+        .iter()
+        .map(|(token, watch)| (format!("{}\0", token), watch.path.clone()))
+        .collect::<Vec<_>>();
+
+    for (token, path) in watches {
+        request(MessageKind::Watch, &[path.as_bytes(), token.as_bytes()], 0).await;
+    }
+}
+
+/// Update the XenBus event channel from the start info structure after resuming in a new domain
+pub(crate) fn update_event_channel() {
+    XENBUS.lock().event_channel = unsafe { *START_INFO }.store_evtchn;
+}
+
+/// Waits for the next event of the watch registered with `token`, resolving to the path that changed
+pub fn watch_event(token: &str) -> WatchEvent<'_> {
+    WatchEvent {
+        token: token.trim_end_matches('\0'),
+    }
+}
+
+/// Blocks until the next event of the watch registered with `token`, giving up at `deadline` in nanoseconds of monotonic time
+///
+/// For use outside of tasks, such as from shutdown handlers, which cannot await `watch_event`.
+pub fn wait_watch_event(token: &str, deadline: u64) -> Option<String> {
+    let token = token.trim_end_matches('\0');
+
+    loop {
+        task::poll();
+
+        if let Some(path) = next_watch_event(token) {
+            return Some(path);
+        }
+
+        if get_monotonic_time() >= deadline {
+            return None;
+        }
+
+        schedule_operation(Command::Yield);
+    }
+}
+
+/// Takes the oldest queued event of the watch registered with `token`
+fn next_watch_event(token: &str) -> Option<String> {
+    XENBUS
+        .lock()
+        .watches
+        .get_mut(token)
+        .and_then(|watch| watch.events.pop_front())
+}
+
+/// Queues a watch event received outside of XenBus, such as by the synchronous XenStore interface
+pub(crate) fn queue_watch_event(data: &[u8]) {
+    XENBUS.lock().queue_watch_event(data)
+}
+
+/// Runs `f` with XenBus locked if no request is awaiting its reply, returning `None` if it is in use
+///
+/// Allows the synchronous XenStore interface to share the ring without blocking, as the reply to a
+/// XenBus request could otherwise be read in place of its own.
+pub(crate) fn try_while_idle<R>(f: impl FnOnce() -> R) -> Option<R> {
+    let xenbus = XENBUS.try_lock()?;
+
+    if xenbus.in_flight > 0 {
+        return None;
+    }
+
+    let result = f();
+    drop(xenbus);
+
+    Some(result)
+}
+
+/// Future returned by `watch_event`
+pub struct WatchEvent<'a> {
+    token: &'a str,
+}
+
+impl Future for WatchEvent<'_> {
+    type Output = String;
+
+    fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
+        task::poll();
+
+        match next_watch_event(self.token) {
+            Some(path) => Poll::Ready(path),
+            None => Poll::Pending,
+        }
+    }
+}
+
 #[derive(Debug)]
 struct XenBus {
     interface: &'static mut xenstore_domain_interface,
     event_channel: u32,
     responses: BTreeMap<u32, (MessageHeader, String)>,
+    /// Registered watches, indexed by token
+    watches: BTreeMap<String, Watch>,
+    /// Number of requests written whose reply has not yet been read
+    in_flight: usize,
+}
+
+/// Registered XenStore watch
+#[derive(Debug)]
+struct Watch {
+    /// Nul-terminated path being watched
+    path: String,
+    /// Paths of fired events not yet received
+    events: VecDeque<String>,
 }
 
 impl XenBus {
@@ -106,6 +245,24 @@ impl XenBus {
         event_channel_op(EVTCHNOP_send, &op as *const _ as u64);
     }
 
+    /// Queue the path of a watch event, `data` containing the nul-separated path and token
+    fn queue_watch_event(&mut self, data: &[u8]) {
+        let mut parts = data.split(|&b| b == 0).map(str::from_utf8);
+
+        let (path, token) = match (parts.next(), parts.next()) {
+            (Some(Ok(path)), Some(Ok(token))) => (path, token),
+            _ => {
+                warn!("Malformed XenBus watch event: {:?}", data);
+                return;
+            }
+        };
+
+        match self.watches.get_mut(token) {
+            Some(watch) => watch.events.push_back(path.to_owned()),
+            None => debug!("Discarding event for unknown watch {:?}: {}", token, path),
+        }
+    }
+
     fn write(&mut self, header: MessageHeader, data: &[&[u8]]) {
         let mut m = xsd_sockmsg::from(header);
         m.len = data.iter().map(|s| s.len() as u32).sum();
@@ -173,6 +330,7 @@ impl XenBus {
         fence(Ordering::SeqCst);
 
         self.interface.req_prod += len;
+        self.in_flight += 1;
 
         self.notify();
     }
@@ -196,7 +354,7 @@ unsafe fn copy_from_ring<T: Copy>(ring: &[T], destination: &mut [T], offset: usi
 }
 
 /// State of XenBus connection
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     /// Initial state of the device on the bus, before either end has been connected
     Unknown,
@@ -11,8 +11,18 @@ use {
 
 /// XenBus background task
 ///
-/// Usually runs in a loop processing XenBus responses and events asynchronously, currently repurposed to block until a single response is read
+/// Usually runs in a loop processing XenBus responses and events asynchronously, currently repurposed to block until a single message is read
 pub fn task() {
+    while !process() {}
+}
+
+/// Process all messages currently available without blocking
+pub fn poll() {
+    while process() {}
+}
+
+/// Process a single message if a complete one is available, returning whether one was processed
+fn process() -> bool {
     let mut msg = xsd_sockmsg {
         type_: 0,
         req_id: 0,
@@ -20,72 +30,69 @@ pub fn task() {
         len: 0,
     };
 
-    loop {
-        let mut xb = XENBUS.lock();
+    let mut xb = XENBUS.lock();
 
-        if (xb.interface.rsp_prod - xb.interface.rsp_cons) < size_of::<xsd_sockmsg>() as u32 {
-            continue;
-        }
+    if (xb.interface.rsp_prod - xb.interface.rsp_cons) < size_of::<xsd_sockmsg>() as u32 {
+        return false;
+    }
 
-        unsafe {
-            copy_from_ring(
-                &xb.interface.rsp,
-                slice::from_raw_parts_mut(&mut msg as *mut _ as *mut _, size_of::<xsd_sockmsg>()),
-                mask_xenstore_idx(xb.interface.rsp_cons) as usize,
-                size_of::<xsd_sockmsg>(),
-            )
-        };
-
-        if xb.interface.rsp_prod - xb.interface.rsp_cons < size_of::<xsd_sockmsg>() as u32 + msg.len
-        {
-            continue;
-        }
+    unsafe {
+        copy_from_ring(
+            &xb.interface.rsp,
+            slice::from_raw_parts_mut(&mut msg as *mut _ as *mut _, size_of::<xsd_sockmsg>()),
+            mask_xenstore_idx(xb.interface.rsp_cons) as usize,
+            size_of::<xsd_sockmsg>(),
+        )
+    };
 
-        if msg.type_ == xsd_sockmsg_type_XS_WATCH_EVENT {
-            unimplemented!();
-        } else {
-            let mut data = vec![0; msg.len as usize];
-
-            unsafe {
-                copy_from_ring(
-                    &xb.interface.rsp,
-                    data.as_mut_slice(),
-                    mask_xenstore_idx(xb.interface.rsp_cons + size_of::<xsd_sockmsg>() as u32)
-                        as usize,
-                    msg.len as usize,
-                )
-            };
-
-            // remove trailing null byte
-            if let Some(0) = data.last() {
-                data.truncate(data.len() - 1);
-            }
-
-            // convert from Vec<i8> to Vec<u8>
-            let data = {
-                let mut v = ManuallyDrop::new(data);
-
-                let p = v.as_mut_ptr();
-                let len = v.len();
-                let cap = v.capacity();
-
-                unsafe { Vec::from_raw_parts(p as *mut u8, len, cap) }
-            };
-
-            // convert to String
-            let contents = String::from_utf8(data).expect("XenBus returned invalid UTF-8");
-
-            xb.responses.insert(msg.req_id, (msg.into(), contents));
-        }
+    if xb.interface.rsp_prod - xb.interface.rsp_cons < size_of::<xsd_sockmsg>() as u32 + msg.len {
+        return false;
+    }
 
-        fence(Ordering::SeqCst);
+    let mut data = vec![0; msg.len as usize];
 
-        xb.interface.rsp_cons += size_of::<xsd_sockmsg>() as u32 + msg.len;
+    unsafe {
+        copy_from_ring(
+            &xb.interface.rsp,
+            data.as_mut_slice(),
+            mask_xenstore_idx(xb.interface.rsp_cons + size_of::<xsd_sockmsg>() as u32) as usize,
+            msg.len as usize,
+        )
+    };
 
-        fence(Ordering::SeqCst);
+    // convert from Vec<i8> to Vec<u8>
+    let mut data = {
+        let mut v = ManuallyDrop::new(data);
 
-        xb.notify();
+        let p = v.as_mut_ptr();
+        let len = v.len();
+        let cap = v.capacity();
 
-        return;
+        unsafe { Vec::from_raw_parts(p as *mut u8, len, cap) }
+    };
+
+    if msg.type_ == xsd_sockmsg_type_XS_WATCH_EVENT {
+        xb.queue_watch_event(&data);
+    } else {
+        // remove trailing null byte
+        if let Some(0) = data.last() {
+            data.truncate(data.len() - 1);
+        }
+
+        // convert to String
+        let contents = String::from_utf8(data).expect("XenBus returned invalid UTF-8");
+
+        xb.responses.insert(msg.req_id, (msg.into(), contents));
+        xb.in_flight = xb.in_flight.saturating_sub(1);
     }
+
+    fence(Ordering::SeqCst);
+
+    xb.interface.rsp_cons += size_of::<xsd_sockmsg>() as u32 + msg.len;
+
+    fence(Ordering::SeqCst);
+
+    xb.notify();
+
+    true
 }
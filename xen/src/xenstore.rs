@@ -9,21 +9,23 @@ use {
     crate::{
         events::event_channel_op,
         memory::{MachineFrameNumber, VirtualAddress},
-        START_INFO,
+        xenbus, START_INFO,
     },
     alloc::{borrow::ToOwned, string::String, vec, vec::Vec},
     core::{
+        cmp::min,
         convert::{TryFrom, TryInto},
         str,
         sync::atomic::{fence, Ordering},
     },
+    displaydoc::Display,
     lazy_static::lazy_static,
     log::{debug, error},
     spin::Mutex,
     xen_sys::{
         evtchn_port_t, evtchn_send, xenstore_domain_interface, xsd_sockmsg,
-        xsd_sockmsg_type_XS_DIRECTORY, xsd_sockmsg_type_XS_READ, xsd_sockmsg_type_XS_WRITE,
-        EVTCHNOP_send, XENSTORE_RING_SIZE,
+        xsd_sockmsg_type_XS_DIRECTORY, xsd_sockmsg_type_XS_READ, xsd_sockmsg_type_XS_WATCH_EVENT,
+        xsd_sockmsg_type_XS_WRITE, EVTCHNOP_send, XENSTORE_PAYLOAD_MAX, XENSTORE_RING_SIZE,
     },
 };
 
@@ -53,24 +55,53 @@ lazy_static! {
     };
 }
 
+/// XenStore error
+#[derive(Display, Debug)]
+pub enum Error {
+    /// Request payload of {0} bytes exceeds the maximum XenStore payload
+    TooLong(usize),
+}
+
 /// Initialize XenStore
 pub fn init() {
     lazy_static::initialize(&XENSTORE);
     debug!("Initialized XenStore");
 }
 
+/// Update the XenStore event channel from the start info structure after resuming in a new domain
+pub(crate) fn resume() {
+    XENSTORE.lock().event_channel_port = unsafe { *START_INFO }.store_evtchn;
+}
+
 /// Write a key-value pair to the XenStore
-pub fn write<K: AsRef<str>, V: AsRef<str>>(key: K, value: V) {
-    XENSTORE.lock().write(key.as_ref(), value.as_ref())
+pub fn write<K: AsRef<str>, V: AsRef<str>>(key: K, value: V) -> Result<(), Error> {
+    XENSTORE.lock().write(key.as_ref(), value.as_ref(), true)
+}
+
+/// Write a key-value pair to the XenStore without blocking, returning `false` if it or XenBus is in use
+/// or the request is too long
+///
+/// Does not allocate, discarding any watch events that arrive before the reply, so can be used while panicking.
+pub fn try_write<K: AsRef<str>, V: AsRef<str>>(key: K, value: V) -> bool {
+    let mut xenstore = match XENSTORE.try_lock() {
+        Some(xenstore) => xenstore,
+        None => return false,
+    };
+
+    // the reply to a XenBus request shares the ring, so could otherwise be read in place of this one
+    matches!(
+        xenbus::try_while_idle(|| xenstore.write(key.as_ref(), value.as_ref(), false)),
+        Some(Ok(()))
+    )
 }
 
 /// Read a key's value from the XenStore
-pub fn read<K: AsRef<str>>(key: K) -> String {
+pub fn read<K: AsRef<str>>(key: K) -> Result<String, Error> {
     XENSTORE.lock().read(key.as_ref())
 }
 
 /// List contents of directory
-pub fn ls<K: AsRef<str>>(key: K) -> Vec<String> {
+pub fn ls<K: AsRef<str>>(key: K) -> Result<Vec<String>, Error> {
     XENSTORE.lock().ls(key.as_ref())
 }
 
@@ -91,15 +122,13 @@ struct XenStore {
 unsafe impl Send for XenStore {}
 
 impl XenStore {
-    /// Write a key-value pair to the XenStore
-    fn write(&mut self, key: &str, value: &str) {
+    /// Write a key-value pair to the XenStore, passing watch events received before the reply on to XenBus if `queue_events`
+    fn write(&mut self, key: &str, value: &str, queue_events: bool) -> Result<(), Error> {
         let msg = &mut xsd_sockmsg {
             type_: xsd_sockmsg_type_XS_WRITE,
             req_id: self.req_id,
             tx_id: 0,
-            len: (key.len() + value.len())
-                .try_into()
-                .expect("Failed to convert usize to u32"),
+            len: payload_len(key.len() + value.len())?,
         };
 
         //TODO: validate that this is safe
@@ -115,7 +144,7 @@ impl XenStore {
 
         self.notify();
 
-        self.read_response(msg_bytes);
+        self.read_reply_header(msg_bytes, queue_events);
         self.ignore(msg.len.try_into().expect("Failed to convert u32 to usize"));
 
         self.req_id += 1;
@@ -123,20 +152,19 @@ impl XenStore {
         if msg.req_id != (self.req_id - 1) {
             panic!("XenStore read failed due to unexpected message request ID");
         }
+
+        Ok(())
     }
 
     /// Perform initial steps of a read operation, returning the length of value now ready to be read
     ///
     /// The read operation was split like this to allow for building operations requiring reads without allocating (e.g. domain_id)
-    fn read_preamble(&mut self, key: &str) -> usize {
+    fn read_preamble(&mut self, key: &str) -> Result<usize, Error> {
         let msg = &mut xsd_sockmsg {
             type_: xsd_sockmsg_type_XS_READ,
             req_id: self.req_id,
             tx_id: 0,
-            len: key
-                .len()
-                .try_into()
-                .expect("Failed to convert usize to u32"),
+            len: payload_len(key.len())?,
         };
 
         //TODO: validate that this is safe
@@ -151,7 +179,7 @@ impl XenStore {
 
         self.notify();
 
-        self.read_response(msg_bytes);
+        self.read_reply_header(msg_bytes, true);
 
         let msg_len = msg.len.try_into().expect("Failed to convert u32 to usize");
         self.req_id += 1;
@@ -161,14 +189,14 @@ impl XenStore {
             panic!("XenStore read failed due to unexpected message request ID");
         }
 
-        msg_len
+        Ok(msg_len)
     }
 
     /// Read a key's value from the XenStore
     ///
     /// Requires that the allocator be initialised before calling
-    fn read(&mut self, key: &str) -> String {
-        let msg_len = self.read_preamble(key);
+    fn read(&mut self, key: &str) -> Result<String, Error> {
+        let msg_len = self.read_preamble(key)?;
 
         let mut buf = vec![0; msg_len];
 
@@ -180,19 +208,16 @@ impl XenStore {
         }
 
         // does not reallocate
-        String::from_utf8(buf).expect("XenStore value contains invalid UTF-8")
+        Ok(String::from_utf8(buf).expect("XenStore value contains invalid UTF-8"))
     }
 
     /// List contents of directory
-    fn ls(&mut self, key: &str) -> Vec<String> {
+    fn ls(&mut self, key: &str) -> Result<Vec<String>, Error> {
         let msg = &mut xsd_sockmsg {
             type_: xsd_sockmsg_type_XS_DIRECTORY,
             req_id: self.req_id,
             tx_id: 0,
-            len: key
-                .len()
-                .try_into()
-                .expect("Failed to convert usize to u32"),
+            len: payload_len(key.len())?,
         };
 
         //TODO: validate that this is safe
@@ -207,7 +232,7 @@ impl XenStore {
 
         self.notify();
 
-        self.read_response(msg_bytes);
+        self.read_reply_header(msg_bytes, true);
 
         let msg_len = msg.len.try_into().expect("Failed to convert u32 to usize");
         self.req_id += 1;
@@ -221,7 +246,7 @@ impl XenStore {
 
         self.read_response(&mut value);
 
-        value
+        Ok(value
             .split(|&c| c == 0)
             .map(|slice| match str::from_utf8(slice) {
                 Ok(str) => str,
@@ -235,7 +260,7 @@ impl XenStore {
             })
             .filter(|s| !s.is_empty())
             .map(|s| s.to_owned())
-            .collect()
+            .collect())
     }
 
     /// Read the current domain's ID
@@ -243,7 +268,9 @@ impl XenStore {
         // fill with newlines so that str::trim removes excess bytes
         let mut buf = [b'\n'; 4];
 
-        let len = self.read_preamble("domid\0");
+        let len = self
+            .read_preamble("domid\0")
+            .expect("Failed to read XenStore domid");
         self.read_response(&mut buf[..len]);
 
         // convert slice to str
@@ -263,19 +290,49 @@ impl XenStore {
         event_channel_op(EVTCHNOP_send, &mut event as *mut _ as u64);
     }
 
-    fn ignore(&mut self, len: usize) {
+    /// Read the header of the next reply, passing any watch events received before it on to XenBus if
+    /// `queue_events` and otherwise discarding them without allocating
+    fn read_reply_header(
+        &mut self,
+        header: &mut [u8; core::mem::size_of::<xsd_sockmsg>()],
+        queue_events: bool,
+    ) {
+        loop {
+            self.read_response(header);
+
+            // type and len are the first and last fields of the header
+            let kind = u32::from_ne_bytes(header[0..4].try_into().unwrap());
+            let len = u32::from_ne_bytes(header[12..16].try_into().unwrap());
+
+            if kind != xsd_sockmsg_type_XS_WATCH_EVENT {
+                return;
+            }
+
+            let len = len.try_into().expect("Failed to convert u32 to usize");
+
+            if !queue_events {
+                self.ignore(len);
+                continue;
+            }
+
+            let mut data = vec![0; len];
+            self.read_response(&mut data);
+            xenbus::queue_watch_event(&data);
+        }
+    }
+
+    fn ignore(&mut self, mut len: usize) {
         let mut buffer = [0u8; XENSTORE_RING_SIZE as usize];
-        self.read_response(&mut buffer[..len]);
+
+        // payloads may be larger than the ring
+        while len > 0 {
+            let chunk = min(len, buffer.len());
+            self.read_response(&mut buffer[..chunk]);
+            len -= chunk;
+        }
     }
 
     fn write_request(&mut self, mut message: &[u8]) {
-        assert!(
-            message.len()
-                < XENSTORE_RING_SIZE
-                    .try_into()
-                    .expect("Failed to convert u32 to usize")
-        );
-
         let mut i = self.interface.req_prod;
         let mut length = message.len();
 
@@ -291,6 +348,11 @@ impl XenStore {
                 {
                     break;
                 }
+
+                // the ring is full, publish what has been written so messages larger than the ring can be consumed
+                fence(Ordering::SeqCst);
+                self.interface.req_prod = i;
+                self.notify();
             }
 
             let ring_index =
@@ -335,6 +397,15 @@ impl XenStore {
     }
 }
 
+/// Checks that a request payload of `len` bytes will be accepted, as XenStore drops the connection otherwise
+fn payload_len(len: usize) -> Result<u32, Error> {
+    if len > usize::try_from(XENSTORE_PAYLOAD_MAX).expect("Failed to convert u32 to usize") {
+        return Err(Error::TooLong(len));
+    }
+
+    Ok(len.try_into().expect("Failed to convert usize to u32"))
+}
+
 fn mask_xenstore_idx(idx: usize) -> usize {
     idx & (usize::try_from(XENSTORE_RING_SIZE).expect("Failed to convert u32 to usize") - 1)
 }